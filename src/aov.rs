@@ -0,0 +1,170 @@
+use std::io::{self, Read, Write};
+
+use crate::checkpoint::{read_f32s, read_u32, write_f32s, write_u32};
+use crate::{Color, Matrix3f, Vector3f};
+
+/// Everything about a camera ray besides its radiance (arbitrary output variables), for
+/// compositing. The geometry comes from the first surface the ray hits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aovs {
+    /// distance along the camera ray to the first hit, infinite for the background
+    pub depth: f32,
+    /// in world space
+    pub position: Vector3f,
+    /// relative to the camera, x right, y up and looking down -z
+    pub camera_position: Vector3f,
+    /// shading normal in world space, facing the camera
+    pub normal: Vector3f,
+    pub albedo: Color,
+    /// index of the object in the scene plus one, 0 for the background
+    pub object_id: u32,
+    /// materials are numbered by name in the order they first show up, 0 for the background
+    pub material_id: u32,
+
+    /// lights and the environment seen directly
+    pub emission: Color,
+    /// light that reflected off one surface on the way to the camera
+    pub direct: Color,
+    /// light that reflected off more than one surface
+    pub indirect: Color,
+    /// direct and indirect light again, split by whether the first surface is diffuse
+    pub diffuse: Color,
+    pub specular: Color,
+}
+
+impl Default for Aovs {
+    fn default() -> Self {
+        Self {
+            depth: f32::INFINITY,
+            position: Vector3f::zeros(),
+            camera_position: Vector3f::zeros(),
+            normal: Vector3f::zeros(),
+            albedo: Color::zeros(),
+            object_id: 0,
+            material_id: 0,
+            emission: Color::zeros(),
+            direct: Color::zeros(),
+            indirect: Color::zeros(),
+            diffuse: Color::zeros(),
+            specular: Color::zeros(),
+        }
+    }
+}
+
+impl Aovs {
+    /// Sort light reaching the camera into the lighting passes. `reflections` is the number of
+    /// surfaces it reflected off, `diffuse` whether the first one of them is diffuse
+    pub fn add_light(&mut self, radiance: Color, reflections: u32, diffuse: bool) {
+        match reflections {
+            0 => {
+                self.emission += radiance;
+                return;
+            }
+            1 => self.direct += radiance,
+            _ => self.indirect += radiance,
+        }
+
+        if diffuse {
+            self.diffuse += radiance;
+        } else {
+            self.specular += radiance;
+        }
+    }
+
+    /// Apply the camera's response to the lighting passes, like the beauty pass
+    pub fn expose(&mut self, response: &Matrix3f) {
+        for pass in [
+            &mut self.emission,
+            &mut self.direct,
+            &mut self.indirect,
+            &mut self.diffuse,
+            &mut self.specular,
+        ] {
+            *pass = response * *pass;
+        }
+    }
+
+    /// Add a sample to a running sum. Depth keeps the closest hit and the IDs come from the
+    /// `first` sample, averaging those wouldn't mean anything
+    pub fn accumulate(&mut self, sample: &Aovs, weight: f32, first: bool) {
+        self.depth = self.depth.min(sample.depth);
+        if first {
+            self.object_id = sample.object_id;
+            self.material_id = sample.material_id;
+        }
+
+        self.position += sample.position * weight;
+        self.camera_position += sample.camera_position * weight;
+        self.normal += sample.normal * weight;
+        self.albedo += sample.albedo * weight;
+
+        self.emission += sample.emission * weight;
+        self.direct += sample.direct * weight;
+        self.indirect += sample.indirect * weight;
+        self.diffuse += sample.diffuse * weight;
+        self.specular += sample.specular * weight;
+    }
+
+    /// The average of a running sum with a total weight of `weight`
+    pub fn resolve(&self, weight: f32) -> Aovs {
+        if weight == 0.0 {
+            return Aovs::default();
+        }
+
+        Aovs {
+            depth: self.depth,
+            position: self.position / weight,
+            camera_position: self.camera_position / weight,
+            normal: self.normal / weight,
+            albedo: self.albedo / weight,
+            object_id: self.object_id,
+            material_id: self.material_id,
+            emission: self.emission / weight,
+            direct: self.direct / weight,
+            indirect: self.indirect / weight,
+            diffuse: self.diffuse / weight,
+            specular: self.specular / weight,
+        }
+    }
+
+    /// The running sum for a checkpoint
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_f32s(out, [self.depth])?;
+        for vector in [
+            &self.position,
+            &self.camera_position,
+            &self.normal,
+            &self.albedo,
+            &self.emission,
+            &self.direct,
+            &self.indirect,
+            &self.diffuse,
+            &self.specular,
+        ] {
+            write_f32s(out, vector.iter().copied())?;
+        }
+        write_u32(out, self.object_id)?;
+        write_u32(out, self.material_id)
+    }
+
+    /// Read back what `write` wrote
+    pub fn read(&mut self, input: &mut impl Read) -> io::Result<()> {
+        read_f32s(input, [&mut self.depth])?;
+        for vector in [
+            &mut self.position,
+            &mut self.camera_position,
+            &mut self.normal,
+            &mut self.albedo,
+            &mut self.emission,
+            &mut self.direct,
+            &mut self.indirect,
+            &mut self.diffuse,
+            &mut self.specular,
+        ] {
+            read_f32s(input, vector.iter_mut())?;
+        }
+        self.object_id = read_u32(input)?;
+        self.material_id = read_u32(input)?;
+        Ok(())
+    }
+}
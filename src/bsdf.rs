@@ -1,44 +1,412 @@
-use crate::{rng::rand_direction, Vector3f};
+use std::f32::consts::PI;
+
+use crate::{sampler::sample_cosine_hemisphere, Color, Vector2f, Vector3f};
 
 pub const UP: Vector3f = Vector3f::new(0.0, 0.0, 1.0);
 
+/// Everything is in normal space, `reflected` is the direction the ray arrived in (pointing
+/// into the surface) and `incedent` the direction light comes from (pointing away from it)
 pub trait BSDF {
-    fn value(&self, incedent: Vector3f, reflected: Vector3f) -> f32;
-    fn sample(&self, reflected: Vector3f) -> Vector3f;
+    fn value(&self, incedent: Vector3f, reflected: Vector3f) -> Color;
+    /// None if the sampled path is absorbed. `u_lobe` picks between reflection and refraction,
+    /// `u` the direction within it
+    fn sample(&self, reflected: Vector3f, u_lobe: f32, u: Vector2f) -> Option<BsdfSample>;
     fn pdf(&self, incedent: Vector3f, reflected: Vector3f) -> f32;
+
+    /// Only reflects or refracts in single directions, so lights can't be sampled for it
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+pub struct BsdfSample {
+    pub incedent: Vector3f,
+    /// value * cos / pdf, what the light arriving from `incedent` gets multiplied by
+    pub weight: Color,
+    /// for delta samples this is the probability of picking that lobe
+    pub pdf: f32,
+    /// the bsdf is zero everywhere except this one direction (mirrors, glass), so `value` and
+    /// `pdf` can't be used for it
+    pub delta: bool,
+}
+
+fn reflect(reflected: Vector3f, normal: Vector3f) -> Vector3f {
+    reflected - 2.0 * reflected.dot(&normal) * normal
 }
 
 pub struct Lambertian {
-    pub albedo: f32,
+    pub albedo: Color,
 }
 
 impl BSDF for Lambertian {
-    fn value(&self, _incedent: Vector3f, _reflected: Vector3f) -> f32 {
-        self.albedo
+    fn value(&self, _incedent: Vector3f, _reflected: Vector3f) -> Color {
+        self.albedo / PI
     }
 
-    fn sample(&self, _reflected: Vector3f) -> Vector3f {
-        rand_direction() + UP
+    fn sample(&self, reflected: Vector3f, _u_lobe: f32, u: Vector2f) -> Option<BsdfSample> {
+        // cosine weighted, the cosine and pdf cancel out
+        let incedent = sample_cosine_hemisphere(u);
+        if incedent.z <= 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            incedent,
+            weight: self.albedo,
+            pdf: self.pdf(incedent, reflected),
+            delta: false,
+        })
     }
 
     fn pdf(&self, incedent: Vector3f, _reflected: Vector3f) -> f32 {
-        incedent.dot(&UP)
+        incedent.dot(&UP).max(0.0) / PI
     }
 }
 
-pub struct Glossy {
-}
+pub struct Glossy {}
 
 impl BSDF for Glossy {
-    fn value(&self, incedent: Vector3f, reflected: Vector3f) -> f32 {
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn value(&self, _incedent: Vector3f, _reflected: Vector3f) -> Color {
+        Color::zeros()
+    }
+
+    fn sample(&self, reflected: Vector3f, _u_lobe: f32, _u: Vector2f) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            incedent: reflect(reflected, UP),
+            weight: Color::repeat(1.0),
+            pdf: 1.0,
+            delta: true,
+        })
+    }
+
+    fn pdf(&self, _incedent: Vector3f, _reflected: Vector3f) -> f32 {
         0.0
     }
+}
+
+/// Smooth glass-like surface that either reflects or refracts, weighted by the Fresnel term
+pub struct Dielectric {
+    /// ratio of the index of refraction on the far side of the surface to the near side
+    pub eta: f32,
+    /// color of the glass, applied every time light passes through the surface
+    pub tint: Color,
+}
+
+/// Fraction of light reflected off a smooth dielectric surface
+/// * `cos_i` - cosine of the angle between the incoming direction and the normal
+/// * `eta` - relative index of refraction, transmitted over incident side
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // total internal reflection
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
 
-    fn sample(&self,reflected: Vector3f) -> Vector3f {
-        reflected - 2.0 * reflected.dot(&UP) * UP
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+impl BSDF for Dielectric {
+    fn is_delta(&self) -> bool {
+        true
     }
 
-    fn pdf(&self, incedent: Vector3f, reflected: Vector3f) -> f32 {
+    fn value(&self, _incedent: Vector3f, _reflected: Vector3f) -> Color {
+        Color::zeros()
+    }
+
+    fn sample(&self, reflected: Vector3f, u_lobe: f32, _u: Vector2f) -> Option<BsdfSample> {
+        let cos_i = -reflected.dot(&UP);
+        let fresnel = fresnel_dielectric(cos_i, self.eta);
+
+        // picking reflection or refraction with probability equal to its weight means both
+        // branches have a throughput of exactly one (before the tint)
+        if u_lobe < fresnel {
+            return Some(BsdfSample {
+                incedent: reflect(reflected, UP),
+                weight: Color::repeat(1.0),
+                pdf: fresnel,
+                delta: true,
+            });
+        }
+
+        let inv_eta = 1.0 / self.eta;
+        let sin2_t = inv_eta * inv_eta * (1.0 - cos_i * cos_i);
+        let cos_t = (1.0 - sin2_t).sqrt();
+
+        Some(BsdfSample {
+            incedent: reflected * inv_eta + (inv_eta * cos_i - cos_t) * UP,
+            weight: self.tint,
+            pdf: 1.0 - fresnel,
+            delta: true,
+        })
+    }
+
+    fn pdf(&self, _incedent: Vector3f, _reflected: Vector3f) -> f32 {
         0.0
     }
-}
\ No newline at end of file
+}
+
+/// Fresnel reflectance of a conductor with complex index of refraction `eta + i k`, per channel
+pub fn fresnel_conductor(cos_i: f32, eta: Color, k: Color) -> Color {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+
+    eta.zip_map(&k, |eta, k| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let t1 = a2b2 + cos2;
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let r_s = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+        0.5 * (r_p + r_s)
+    })
+}
+
+/// Rough metal using the GGX (Trowbridge-Reitz) microfacet distribution
+pub struct Conductor {
+    /// GGX alpha, the square of the perceptual roughness
+    pub alpha: f32,
+    pub eta: Color,
+    pub k: Color,
+    /// multiplied on top of the fresnel term
+    pub tint: Color,
+}
+
+impl Conductor {
+    pub fn new(roughness: f32, eta: Color, k: Color, tint: Color) -> Self {
+        Self {
+            alpha: roughness * roughness,
+            eta,
+            k,
+            tint,
+        }
+    }
+
+    /// below this the surface is treated as a perfect mirror
+    fn is_smooth(&self) -> bool {
+        self.alpha < 1e-3
+    }
+
+    fn fresnel(&self, cos_i: f32) -> Color {
+        fresnel_conductor(cos_i, self.eta, self.k).component_mul(&self.tint)
+    }
+
+    /// GGX normal distribution
+    fn d(&self, half: Vector3f) -> f32 {
+        let alpha2 = self.alpha * self.alpha;
+        let cos2 = half.z * half.z;
+        let denom = cos2 * (alpha2 - 1.0) + 1.0;
+
+        alpha2 / (PI * denom * denom)
+    }
+
+    /// Smith's auxiliary function for GGX
+    fn lambda(&self, w: Vector3f) -> f32 {
+        let cos2 = w.z * w.z;
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) / 2.0
+    }
+
+    fn g1(&self, w: Vector3f) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    fn g2(&self, outgoing: Vector3f, incedent: Vector3f) -> f32 {
+        1.0 / (1.0 + self.lambda(outgoing) + self.lambda(incedent))
+    }
+
+    /// Sample a microfacet normal from the distribution of normals visible from `outgoing`,
+    /// "Sampling the GGX Distribution of Visible Normals" (Heitz 2018)
+    fn sample_visible_normal(&self, outgoing: Vector3f, u1: f32, u2: f32) -> Vector3f {
+        // stretch into the hemisphere configuration
+        let v = Vector3f::new(self.alpha * outgoing.x, self.alpha * outgoing.y, outgoing.z)
+            .normalize();
+
+        let len2 = v.x * v.x + v.y * v.y;
+        let t1 = if len2 > 0.0 {
+            Vector3f::new(-v.y, v.x, 0.0) / len2.sqrt()
+        } else {
+            Vector3f::new(1.0, 0.0, 0.0)
+        };
+        let t2 = v.cross(&t1);
+
+        // sample the projected disk
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let p2 = r * phi.sin();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * p2;
+
+        // reproject onto the hemisphere and unstretch
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+
+        Vector3f::new(self.alpha * n.x, self.alpha * n.y, n.z.max(1e-6)).normalize()
+    }
+}
+
+impl BSDF for Conductor {
+    fn is_delta(&self) -> bool {
+        self.is_smooth()
+    }
+
+    fn value(&self, incedent: Vector3f, reflected: Vector3f) -> Color {
+        let outgoing = -reflected;
+        if self.is_smooth() || incedent.z <= 0.0 || outgoing.z <= 0.0 {
+            return Color::zeros();
+        }
+
+        let Some(half) = (incedent + outgoing).try_normalize(1e-6) else {
+            return Color::zeros();
+        };
+
+        let fresnel = self.fresnel(incedent.dot(&half));
+        fresnel * self.d(half) * self.g2(outgoing, incedent) / (4.0 * incedent.z * outgoing.z)
+    }
+
+    fn sample(&self, reflected: Vector3f, _u_lobe: f32, u: Vector2f) -> Option<BsdfSample> {
+        let outgoing = -reflected;
+        if outgoing.z <= 0.0 {
+            return None;
+        }
+
+        if self.is_smooth() {
+            return Some(BsdfSample {
+                incedent: reflect(reflected, UP),
+                weight: self.fresnel(outgoing.z),
+                pdf: 1.0,
+                delta: true,
+            });
+        }
+
+        let half = self.sample_visible_normal(outgoing, u.x, u.y);
+        let incedent = reflect(reflected, half);
+        if incedent.z <= 0.0 {
+            return None;
+        }
+
+        // value * cos / pdf simplifies to F * G2 / G1 with visible normal sampling
+        let fresnel = self.fresnel(incedent.dot(&half));
+        let weight = fresnel * (self.g2(outgoing, incedent) / self.g1(outgoing));
+
+        Some(BsdfSample {
+            incedent,
+            weight,
+            pdf: self.pdf(incedent, reflected),
+            delta: false,
+        })
+    }
+
+    fn pdf(&self, incedent: Vector3f, reflected: Vector3f) -> f32 {
+        let outgoing = -reflected;
+        if self.is_smooth() || incedent.z <= 0.0 || outgoing.z <= 0.0 {
+            return 0.0;
+        }
+
+        let Some(half) = (incedent + outgoing).try_normalize(1e-6) else {
+            return 0.0;
+        };
+
+        // visible normal pdf D_o(h) = G1(o) * max(0, o.h) * D(h) / o.z, and the reflection
+        // jacobian 1 / (4 * o.h) cancels the o.h
+        self.g1(outgoing) * self.d(half) / (4.0 * outgoing.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresnel_dielectric_limits() {
+        // ((eta - 1) / (eta + 1))^2 head on
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert_eq!(fresnel_dielectric(1.0, 1.0), 0.0);
+
+        // everything reflects at grazing angles, and past the critical angle going out
+        assert!((fresnel_dielectric(0.0, 1.5) - 1.0).abs() < 1e-6);
+        assert_eq!(fresnel_dielectric(0.1, 1.0 / 1.5), 1.0);
+    }
+
+    #[test]
+    fn fresnel_conductor_limits() {
+        let eta = Color::new(0.2, 1.0, 1.5);
+        let k = Color::new(3.9, 2.4, 0.0);
+
+        let normal = fresnel_conductor(1.0, eta, k);
+        for i in 0..3 {
+            let expected =
+                ((eta[i] - 1.0).powi(2) + k[i] * k[i]) / ((eta[i] + 1.0).powi(2) + k[i] * k[i]);
+            assert!(
+                (normal[i] - expected).abs() < 1e-5,
+                "{} {}",
+                normal[i],
+                expected
+            );
+        }
+
+        let grazing = fresnel_conductor(0.0, eta, k);
+        assert!((grazing - Color::repeat(1.0)).amax() < 1e-5, "{}", grazing);
+
+        // without absorption it's just a dielectric
+        for cos_i in [0.2, 0.5, 0.9] {
+            let conductor = fresnel_conductor(cos_i, Color::repeat(1.5), Color::zeros());
+            assert!((conductor.x - fresnel_dielectric(cos_i, 1.5)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn ggx_sample_pdf_matches_pdf() {
+        let conductor = Conductor::new(
+            0.7,
+            Color::repeat(0.2),
+            Color::repeat(3.0),
+            Color::repeat(1.0),
+        );
+        let n = 256;
+
+        for outgoing in [
+            UP,
+            Vector3f::new(0.8, 0.0, 0.6),
+            Vector3f::new(0.0, 0.96, 0.28),
+        ] {
+            let mut solid_angle = 0.0;
+            for i in 0..n {
+                for j in 0..n {
+                    let u = Vector2f::new((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                    let Some(sample) = conductor.sample(-outgoing, 0.0, u) else {
+                        continue;
+                    };
+
+                    let pdf = conductor.pdf(sample.incedent, -outgoing);
+                    assert!((sample.pdf - pdf).abs() <= 1e-4 * pdf);
+                    solid_angle += 1.0 / pdf as f64;
+                }
+            }
+
+            // if the samples really have that density, one over it averages to the solid angle
+            // they can end up in, the whole hemisphere
+            let solid_angle = solid_angle / (n * n) as f64;
+            let expected = 2.0 * std::f64::consts::PI;
+            assert!(
+                (solid_angle - expected).abs() < 0.02 * expected,
+                "{}",
+                solid_angle
+            );
+        }
+    }
+}
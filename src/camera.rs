@@ -1,4 +1,12 @@
-use crate::{geom::Transform, Matrix4f, Projective, Vector3d};
+use std::f32::consts::PI;
+
+use crate::color::white_balance;
+use crate::sampler::sample_disk;
+use crate::texture::equirectangular_direction;
+use crate::{
+    geom::normalize, geom::Transform, Matrix3f, Matrix4f, Point3f, Projective, Ray, Vector2f,
+};
+use crate::{Vector3d, Vector3f};
 
 pub const UP: Vector3d = Vector3d::new(0.0, 0.0, 1.0);
 
@@ -19,6 +27,103 @@ pub fn perspective(fov: f32, aspect: f32) -> Projective {
     Projective::from_matrix_unchecked(out)
 }
 
+/// How directions around the camera are laid out on the image
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Projection {
+    /// uses the camera's projection matrix
+    #[default]
+    Perspective,
+    /// parallel rays, `height` is how much of the scene fits vertically in scene units
+    Orthographic { height: f32 },
+    /// angle from the center grows linearly with the distance from the center of the image.
+    /// The image circle fits the shorter side and covers `fov` (in radians)
+    FisheyeEquidistant { fov: f32 },
+    /// keeps areas proportional to solid angles, same image circle as the equidistant one
+    FisheyeEquisolid { fov: f32 },
+    /// full 360 by 180 degree panorama with the view direction in the middle
+    Equirectangular,
+    /// omni-directional stereo panorama for VR, the left eye on the top half and the right eye
+    /// on the bottom. `ipd` is the distance between the eyes
+    Stereo { ipd: f32 },
+}
+
+/// Direction for a point on an equirectangular panorama with the view direction (-z) in the
+/// middle and +y up, `ndc` goes from -1 to 1 on both axes
+fn panorama_direction(ndc: Vector2f) -> Vector3f {
+    let uv = Vector2f::new((ndc.x + 1.0) / 2.0, (1.0 - ndc.y) / 2.0);
+
+    // the texture lookup has z up and the center of the image along +y
+    let d = equirectangular_direction(uv);
+    Vector3f::new(d.x, d.z, -d.y)
+}
+
+/// Thin lens in front of the camera, the default is a pinhole with everything in focus
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lens {
+    /// radius of the aperture in scene units
+    pub aperture_radius: f32,
+    /// distance to the plane that's in focus
+    pub focus_distance: f32,
+    /// number of aperture blades, less than three gives a round aperture
+    pub blades: u32,
+    /// in radians
+    pub blade_rotation: f32,
+}
+
+impl Lens {
+    /// Point on the aperture for a uniform `u`, distributed evenly over its area
+    fn sample_aperture(&self, u: Vector2f) -> Vector2f {
+        if self.blades < 3 {
+            return sample_disk(u) * self.aperture_radius;
+        }
+
+        // pick one of the triangles between the center and two neighbouring corners, they
+        // all have the same area
+        let n = self.blades as f32;
+        let blade = (u.x * n).floor().min(n - 1.0);
+        let u_x = u.x * n - blade;
+
+        let corner = |i: f32| {
+            let angle = 2.0 * PI * i / n + self.blade_rotation;
+            Vector2f::new(angle.cos(), angle.sin()) * self.aperture_radius
+        };
+
+        let su = u_x.sqrt();
+        corner(blade) * (su * (1.0 - u.y)) + corner(blade + 1.0) * (su * u.y)
+    }
+}
+
+/// How much of the light reaching the camera ends up in the image
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Exposure {
+    /// radiance goes into the image as it is
+    #[default]
+    Raw,
+    /// exposure value at ISO 100, higher values for brighter scenes
+    Ev100(f32),
+    /// settings of a real camera, `shutter` is in seconds
+    Manual { iso: f32, shutter: f32, f_stop: f32 },
+}
+
+impl Exposure {
+    /// Factor from scene radiance to image values. Uses the saturation based sensitivity, so
+    /// the brightest value a sensor can hold without clipping comes out as 1.
+    /// "Moving Frostbite to Physically Based Rendering" (Lagarde and de Rousiers 2014)
+    pub fn scale(self) -> f32 {
+        let ev100 = match self {
+            Exposure::Raw => return 1.0,
+            Exposure::Ev100(ev100) => ev100,
+            Exposure::Manual {
+                iso,
+                shutter,
+                f_stop,
+            } => (f_stop * f_stop / shutter * 100.0 / iso).log2(),
+        };
+
+        1.0 / (1.2 * 2.0f32.powf(ev100))
+    }
+}
+
 pub struct Camera {
     pub width: usize,
     pub height: usize,
@@ -26,6 +131,12 @@ pub struct Camera {
     pub transform: Transform,
     pub projection: Projective,
     pub inv_projection: Projective,
+    pub kind: Projection,
+    pub lens: Lens,
+
+    pub exposure: Exposure,
+    /// color temperature in Kelvin that should come out white, None leaves colors alone
+    pub white_balance: Option<f32>,
 }
 
 impl Camera {
@@ -38,6 +149,138 @@ impl Camera {
 
             projection,
             inv_projection: projection.inverse(),
+            kind: Projection::default(),
+            lens: Lens::default(),
+
+            exposure: Exposure::default(),
+            white_balance: None,
         }
     }
+
+    /// Ray through the point `film` (in pixels, from the top left corner of the image) in the
+    /// same space as the BVH. `u_lens` picks the point on the lens it starts from.
+    /// None for points outside the image circle of fisheye cameras
+    pub fn generate_ray(&self, film: Vector2f, u_lens: Vector2f) -> Option<Ray> {
+        let ndc_x = (2.0 * film.x) / self.width as f32 - 1.0;
+        let ndc_y = 1.0 - (2.0 * film.y) / self.height as f32;
+        let aspect = self.width as f32 / self.height as f32;
+
+        // the camera looks down -z here
+        let (mut origin, mut ray_dir) = match self.kind {
+            Projection::Perspective => {
+                let ndc_point = Point3f::new(ndc_x, ndc_y, 1.0);
+                let camera_space_point = self.inv_projection.transform_point(&ndc_point);
+
+                (Vector3f::zeros(), camera_space_point.coords)
+            }
+            Projection::Orthographic { height } => (
+                Vector3f::new(ndc_x * aspect, ndc_y, 0.0) * (height / 2.0),
+                Vector3f::new(0.0, 0.0, -1.0),
+            ),
+            Projection::FisheyeEquidistant { fov } | Projection::FisheyeEquisolid { fov } => {
+                // 1 at the edge of the image circle
+                let p = if aspect >= 1.0 {
+                    Vector2f::new(ndc_x * aspect, ndc_y)
+                } else {
+                    Vector2f::new(ndc_x, ndc_y / aspect)
+                };
+                let r = p.norm();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta = match self.kind {
+                    Projection::FisheyeEquidistant { .. } => r * fov / 2.0,
+                    _ => 2.0 * (r * (fov / 4.0).sin()).clamp(-1.0, 1.0).asin(),
+                };
+                let phi = p.y.atan2(p.x);
+
+                (
+                    Vector3f::zeros(),
+                    Vector3f::new(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        -theta.cos(),
+                    ),
+                )
+            }
+            Projection::Equirectangular => (
+                Vector3f::zeros(),
+                panorama_direction(Vector2f::new(ndc_x, ndc_y)),
+            ),
+            Projection::Stereo { ipd } => {
+                // each eye gets half the image
+                let left = ndc_y > 0.0;
+                let eye_y = if left {
+                    ndc_y * 2.0 - 1.0
+                } else {
+                    ndc_y * 2.0 + 1.0
+                };
+                let direction = panorama_direction(Vector2f::new(ndc_x, eye_y));
+
+                // the eyes sit on a circle, looking out sideways from it
+                let right = Vector3f::new(-direction.z, 0.0, direction.x)
+                    .try_normalize(1e-6)
+                    .unwrap_or(Vector3f::new(1.0, 0.0, 0.0));
+                let side = if left { -0.5 } else { 0.5 };
+
+                (right * (ipd * side), direction)
+            }
+        };
+
+        // depth of field, only for cameras that look in one general direction
+        if self.lens.aperture_radius > 0.0 && ray_dir.z < 0.0 {
+            if let Projection::Perspective | Projection::Orthographic { .. } = self.kind {
+                // everything along the pinhole ray at the focus distance stays sharp
+                let focus = origin + ray_dir * (self.lens.focus_distance / -ray_dir.z);
+                let lens = self.lens.sample_aperture(u_lens);
+
+                origin += Vector3f::new(lens.x, lens.y, 0.0);
+                ray_dir = focus - origin;
+            }
+        }
+
+        let wow = self.transform.inv_matrix_f;
+        let to_bvh = |v: Vector3f| wow.transform_vector(&wow.transform_vector(&v));
+
+        let ray_dir = normalize(to_bvh(ray_dir));
+
+        Some(Ray {
+            origin: to_bvh(origin).into(),
+            direction: ray_dir,
+            inv_direction: Vector3f::new(1.0 / ray_dir.x, 1.0 / ray_dir.y, 1.0 / ray_dir.z),
+        })
+    }
+
+    /// Position or direction relative to the camera in view space (x right, y up, looking down
+    /// -z) for one in the same space as the BVH, the opposite of what `generate_ray` does
+    pub fn bvh_to_view(&self, v: Vector3f) -> Vector3f {
+        let m = self.transform.matrix_f;
+        m.transform_vector(&m.transform_vector(&v))
+    }
+
+    /// Linear transform from scene radiance to the values stored in the image, exposure and
+    /// white balance together
+    pub fn sensor_response(&self) -> Matrix3f {
+        let white_balance = self
+            .white_balance
+            .map(white_balance)
+            .unwrap_or(Matrix3f::identity());
+
+        white_balance * self.exposure.scale()
+    }
+
+    /// Change the output resolution, keeping the vertical field of view
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let old_aspect = self.width as f32 / self.height as f32;
+        let new_aspect = width as f32 / height as f32;
+
+        let mut matrix = self.projection.into_inner();
+        matrix[(0, 0)] *= old_aspect / new_aspect;
+
+        self.width = width;
+        self.height = height;
+        self.projection = Projective::from_matrix_unchecked(matrix);
+        self.inv_projection = self.projection.inverse();
+    }
 }
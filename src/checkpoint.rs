@@ -0,0 +1,224 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::film::Film;
+use crate::render::RenderSettings;
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+
+const MAGIC: &[u8; 8] = b"RTCKPT01";
+
+/// 64 bit FNV-1a, stable across runs and platforms unlike std's hasher
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of everything that decides which samples a render takes: the scene files and the
+/// settings the samples depend on. A checkpoint only continues a render with the same hash
+pub fn render_hash(scene: &Scene, settings: &RenderSettings) -> u64 {
+    let mut hash = Fnv::default();
+    hash.write(&scene.source_hash.to_le_bytes());
+
+    let camera = &scene.camera;
+    for value in [camera.width as u64, camera.height as u64] {
+        hash.write(&value.to_le_bytes());
+    }
+    hash.write(&settings.max_bounces.to_le_bytes());
+    hash.write(&settings.seed.to_le_bytes());
+    hash.write(format!("{:?}", settings.sampler).as_bytes());
+    hash.write(format!("{:?}", settings.filter.kind).as_bytes());
+    hash.write(&settings.filter.radius.to_le_bytes());
+
+    // the strata depend on the sample count, the other samplers can just carry on with more
+    if settings.sampler == SamplerKind::Stratified {
+        hash.write(&settings.samples.to_le_bytes());
+    }
+
+    hash.finish()
+}
+
+/// Write everything the film accumulated so far, `hash` is the `render_hash` of the render.
+/// Goes through a temporary file so a crash while writing doesn't destroy the last checkpoint
+pub fn save(film: &Film, path: impl AsRef<Path>, hash: u64) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(MAGIC)?;
+    write_u64(&mut out, hash)?;
+    write_u64(&mut out, film.width as u64)?;
+    write_u64(&mut out, film.height as u64)?;
+    film.write_state(&mut out)?;
+    out.into_inner()?.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to write checkpoint {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Continue `film` from a checkpoint, the film has to be new and set up for the same render.
+/// Fails if the checkpoint came from a different scene or different settings
+pub fn load(film: &mut Film, path: impl AsRef<Path>, hash: u64) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("failed to read checkpoint {}", path.display()))?;
+    let mut input = BufReader::new(file);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("{} is not a checkpoint", path.display());
+    }
+
+    if read_u64(&mut input)? != hash {
+        bail!(
+            "{} is from a different scene or render settings, not resuming",
+            path.display()
+        );
+    }
+    let width = read_u64(&mut input)? as usize;
+    let height = read_u64(&mut input)? as usize;
+    if (width, height) != (film.width, film.height) {
+        bail!(
+            "{} is {}x{} but the render is {}x{}",
+            path.display(),
+            width,
+            height,
+            film.width,
+            film.height
+        );
+    }
+
+    film.read_state(&mut input)
+        .with_context(|| format!("failed to read checkpoint {}", path.display()))?;
+
+    Ok(())
+}
+
+pub fn write_u32(out: &mut impl Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32s(out: &mut impl Write, values: impl IntoIterator<Item = f32>) -> io::Result<()> {
+    for value in values {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32s<'a>(
+    input: &mut impl Read,
+    values: impl IntoIterator<Item = &'a mut f32>,
+) -> io::Result<()> {
+    let mut bytes = [0; 4];
+    for value in values {
+        input.read_exact(&mut bytes)?;
+        *value = f32::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::PixelSample;
+    use crate::Color;
+
+    fn film_with_samples() -> Film {
+        let mut film = Film::new(4, 3);
+        for pass in 0..3 {
+            let samples: Vec<_> = (0..12)
+                .map(|i| PixelSample {
+                    radiance: Color::new(i as f32, pass as f32, 0.5),
+                    weight: 0.5 + pass as f32,
+                    aovs: None,
+                })
+                .collect();
+            film.add_pass(&samples);
+        }
+        film
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join("checkpoint-test-round-trip.ckpt");
+        let film = film_with_samples();
+        save(&film, &path, 42).unwrap();
+
+        let mut resumed = Film::new(4, 3);
+        load(&mut resumed, &path, 42).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(resumed.samples, film.samples);
+        for (x, y) in (0..4).flat_map(|x| (0..3).map(move |y| (x, y))) {
+            assert_eq!(resumed.pixel(x, y), film.pixel(x, y));
+            assert_eq!(resumed.weight(x, y), film.weight(x, y));
+        }
+        for i in 0..12 {
+            assert_eq!(resumed.sample_count(i), film.sample_count(i));
+            assert_eq!(resumed.error(i), film.error(i));
+        }
+    }
+
+    #[test]
+    fn rejects_other_renders() {
+        let path = std::env::temp_dir().join("checkpoint-test-other-render.ckpt");
+        save(&film_with_samples(), &path, 42).unwrap();
+
+        let error = load(&mut Film::new(4, 3), &path, 43).unwrap_err();
+        assert!(error.to_string().contains("different scene"), "{}", error);
+
+        let error = load(&mut Film::new(3, 4), &path, 42).unwrap_err();
+        assert!(
+            error.to_string().contains("is 4x3 but the render is 3x4"),
+            "{}",
+            error
+        );
+
+        let error = load(&mut Film::with_aovs(4, 3), &path, 42).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("different AOVs"),
+            "{}",
+            error
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,76 @@
-use crate::Vector3f;
+use std::str::FromStr;
+
+use crate::{Matrix3f, Vector3f};
 
 pub type Color = Vector3f;
 
+/// Relative luminance of a linear Rec.709 color
+pub fn luminance(color: Color) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Chromaticity of a black body at `kelvin` (clamped to 1667K - 25000K), using the cubic fit
+/// of the Planckian locus from Kim et al. 2002
+fn planckian_xy(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.clamp(1667.0, 25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+
+    (x as f32, y as f32)
+}
+
+fn rec709_to_xyz() -> Matrix3f {
+    #[rustfmt::skip]
+    let m = Matrix3f::new(
+        0.4124564, 0.3575761, 0.1804375,
+        0.2126729, 0.7151522, 0.0721750,
+        0.0193339, 0.119192,  0.9503041,
+    );
+    m
+}
+
+/// XYZ to the cone response space of the Bradford chromatic adaptation transform
+fn bradford() -> Matrix3f {
+    #[rustfmt::skip]
+    let m = Matrix3f::new(
+         0.8951,  0.2664, -0.1614,
+        -0.7502,  1.7135,  0.0367,
+         0.0389, -0.0685,  1.0296,
+    );
+    m
+}
+
+/// Linear Rec.709 transform that makes things lit by a black body at `kelvin` look white, like
+/// the white balance setting of a camera. 6504K (roughly D65) leaves colors unchanged, lower
+/// temperatures make the image cooler and higher ones warmer
+pub fn white_balance(kelvin: f32) -> Matrix3f {
+    let cone_response = |kelvin: f32| {
+        let (x, y) = planckian_xy(kelvin);
+        bradford() * Vector3f::new(x / y, 1.0, (1.0 - x - y) / y)
+    };
+
+    let gain = cone_response(6504.0).component_div(&cone_response(kelvin));
+
+    let to_cone = bradford() * rec709_to_xyz();
+    let from_cone = to_cone.try_inverse().unwrap();
+
+    from_cone * Matrix3f::from_diagonal(&gain) * to_cone
+}
+
 fn linear_rec709_to_srgb(c: f32) -> f32 {
     if c <= 0.0031308 {
         12.92 * c
@@ -11,6 +80,15 @@ fn linear_rec709_to_srgb(c: f32) -> f32 {
     }
 }
 
+fn linear_to_rec709(c: f32) -> f32 {
+    if c < 0.018 {
+        4.5 * c
+    } else {
+        1.099 * c.powf(0.45) - 0.099
+    }
+}
+
+/// The curve from "Filmic Tonemapping Operators" (Hable 2010)
 fn filmic(t: f32) -> f32 {
     let a = 0.22;
     let b = 0.3;
@@ -22,25 +100,243 @@ fn filmic(t: f32) -> f32 {
     ((t * (a * t + c * b) + d * e) / (t * (a * t + b) + d * f)) - e / f
 }
 
-// pub fn tonemap(color: Color) -> Color {
-//     let exposure = 0.0;
-//     let color = color * 2.0f32.powf(exposure);
-
-//     // apply filmic tone mapping
-//     let color = Color::new(
-//         filmic(color.x),
-//         filmic(color.y),
-//         filmic(color.z),
-//     );
-
-//     // apply transfer function
-//     Color::new(
-//         linear_rec709_to_srgb(color.x),
-//         linear_rec709_to_srgb(color.y),
-//         linear_rec709_to_srgb(color.z),
-//     )
-// }
-
-pub fn tonemap(color: Color) -> Color {
-    color
-}
\ No newline at end of file
+/// Fit of the ACES reference rendering and sRGB output transforms by Stephen Hill, including
+/// the conversion to and from the ACES working space
+fn aces_fitted(color: Color) -> Color {
+    #[rustfmt::skip]
+    let input = Matrix3f::new(
+        0.59719, 0.35458, 0.04823,
+        0.07600, 0.90834, 0.01566,
+        0.02840, 0.13383, 0.83777,
+    );
+    #[rustfmt::skip]
+    let output = Matrix3f::new(
+         1.60475, -0.53108, -0.07367,
+        -0.10208,  1.10813, -0.00605,
+        -0.00327, -0.07276,  1.07602,
+    );
+
+    let rrt_and_odt =
+        |v: f32| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+
+    output * (input * color).map(rrt_and_odt)
+}
+
+/// AgX by Troy Sobotka, with the polynomial fit of its base contrast curve from Benjamin Wrensch
+fn agx(color: Color) -> Color {
+    #[rustfmt::skip]
+    let inset = Matrix3f::new(
+        0.842479,  0.0784336, 0.0792237,
+        0.0423282, 0.878469,  0.0791661,
+        0.0423757, 0.0784336, 0.879143,
+    );
+    #[rustfmt::skip]
+    let outset = Matrix3f::new(
+         1.196879,  -0.0980209, -0.0990297,
+        -0.0528969,  1.151903,  -0.0989612,
+        -0.0529716, -0.0980435,  1.151074,
+    );
+
+    // the curve covers this many stops below and above middle grey
+    let (min_ev, max_ev) = (-12.47393, 4.026069);
+
+    let contrast = |v: f32| {
+        let x = (v.max(1e-10).log2().clamp(min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    };
+
+    // the curve gives display values with a 2.2 gamma, go back to linear for the transfer
+    // function
+    (outset * (inset * color).map(contrast)).map(|v| v.max(0.0).powf(2.2))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Tonemap {
+    /// just clips at 1
+    Linear,
+    /// extended Reinhard on luminance, `white` is the luminance that ends up at 1
+    Reinhard { white: f32 },
+    /// Uncharted 2 filmic curve
+    Hable,
+    /// fitted ACES, contrasty and saturated
+    Aces,
+    /// desaturates towards white in the highlights, like the Blender default
+    #[default]
+    AgX,
+}
+
+impl FromStr for Tonemap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Tonemap::Linear),
+            "reinhard" => Ok(Tonemap::Reinhard { white: 4.0 }),
+            "hable" | "filmic" => Ok(Tonemap::Hable),
+            "aces" => Ok(Tonemap::Aces),
+            "agx" => Ok(Tonemap::AgX),
+            _ => Err(format!(
+                "unknown tonemapper '{}', expected linear, reinhard, hable, aces or agx",
+                s
+            )),
+        }
+    }
+}
+
+impl Tonemap {
+    /// Map linear scene values to linear display values between 0 and 1
+    pub fn apply(self, color: Color) -> Color {
+        let color = color.map(|c| c.max(0.0));
+
+        let mapped = match self {
+            Tonemap::Linear => color,
+            Tonemap::Reinhard { white } => {
+                let l = luminance(color);
+                if l <= 0.0 {
+                    return Color::zeros();
+                }
+                let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+                color * (mapped / l)
+            }
+            Tonemap::Hable => {
+                // the usual exposure bias and linear white point that go with the curve
+                let white = 11.2;
+                color.map(|c| filmic(c * 2.0) / filmic(white))
+            }
+            Tonemap::Aces => aces_fitted(color),
+            Tonemap::AgX => agx(color),
+        };
+
+        mapped.map(|c| c.clamp(0.0, 1.0))
+    }
+}
+
+/// Transfer function for encoding display values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transfer {
+    #[default]
+    Srgb,
+    Rec709,
+}
+
+impl FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" => Ok(Transfer::Srgb),
+            "rec709" => Ok(Transfer::Rec709),
+            _ => Err(format!(
+                "unknown transfer function '{}', expected srgb or rec709",
+                s
+            )),
+        }
+    }
+}
+
+impl Transfer {
+    pub fn encode(self, color: Color) -> Color {
+        match self {
+            Transfer::Srgb => color.map(linear_rec709_to_srgb),
+            Transfer::Rec709 => color.map(linear_to_rec709),
+        }
+    }
+}
+
+/// Everything between the rendered image and values for a regular display
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisplayTransform {
+    pub tonemap: Tonemap,
+    /// in stops, on top of the camera exposure
+    pub exposure: f32,
+    pub transfer: Transfer,
+}
+
+impl DisplayTransform {
+    /// Encoded display values between 0 and 1 for a rendered color
+    pub fn apply(&self, color: Color) -> Color {
+        let color = color * 2.0f32.powf(self.exposure);
+        self.transfer.encode(self.tonemap.apply(color))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TONEMAPS: [Tonemap; 5] = [
+        Tonemap::Linear,
+        Tonemap::Reinhard { white: 4.0 },
+        Tonemap::Hable,
+        Tonemap::Aces,
+        Tonemap::AgX,
+    ];
+
+    #[test]
+    fn tonemaps_keep_black_and_gray() {
+        for tonemap in TONEMAPS {
+            let black = tonemap.apply(Color::zeros());
+            assert!(black.amax() < 1e-6, "{:?} {}", tonemap, black);
+
+            for gray in [0.01, 0.18, 1.0, 10.0] {
+                let mapped = tonemap.apply(Color::repeat(gray));
+                let spread = mapped.max() - mapped.min();
+                assert!(spread < 1e-3, "{:?} {}", tonemap, mapped);
+            }
+        }
+    }
+
+    #[test]
+    fn tonemap_identities() {
+        let color = Color::new(0.25, 0.5, 0.75);
+        assert_eq!(Tonemap::Linear.apply(color), color);
+        assert_eq!(
+            Tonemap::Linear.apply(Color::new(2.0, -1.0, 0.5)),
+            Color::new(1.0, 0.0, 0.5)
+        );
+
+        // reinhard's white ends up exactly at 1
+        let white = Tonemap::Reinhard { white: 4.0 }.apply(Color::repeat(4.0));
+        assert!((white - Color::repeat(1.0)).amax() < 1e-6, "{}", white);
+    }
+
+    #[test]
+    fn transfers_keep_black_and_white() {
+        for transfer in [Transfer::Srgb, Transfer::Rec709] {
+            assert_eq!(transfer.encode(Color::zeros()), Color::zeros());
+            let white = transfer.encode(Color::repeat(1.0));
+            assert!(
+                (white - Color::repeat(1.0)).amax() < 1e-6,
+                "{:?} {}",
+                transfer,
+                white
+            );
+        }
+    }
+
+    #[test]
+    fn white_balance_identities() {
+        // D65 is what the colors already are
+        let identity = white_balance(6504.0);
+        assert!(
+            (identity - Matrix3f::identity()).amax() < 1e-5,
+            "{}",
+            identity
+        );
+
+        // a warm light gets cooled down, and the other way around
+        let gray = Color::repeat(0.5);
+        let cooled = white_balance(3000.0) * gray;
+        let warmed = white_balance(10000.0) * gray;
+        assert!(
+            cooled.z > cooled.x && warmed.x > warmed.z,
+            "{} {}",
+            cooled,
+            warmed
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+
+use crate::aov::Aovs;
+use crate::checkpoint::{read_f32s, read_u32, write_f32s, write_u32};
+
+/// How many IDs with the most coverage are kept for every pixel, two per RGBA channel set
+pub const RANKS: usize = 6;
+
+/// MurmurHash3 (x86, 32 bit), what Cryptomatte hashes names with
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    let (c1, c2) = (0xcc9e2d51u32, 0x1b873593u32);
+    let mut h = seed;
+
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+
+    for chunk in chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap());
+        h ^= k.wrapping_mul(c1).rotate_left(15).wrapping_mul(c2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, &byte) in tail.iter().enumerate() {
+            k |= (byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(c1).rotate_left(15).wrapping_mul(c2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+
+    h
+}
+
+pub fn hash_name(name: &str) -> u32 {
+    murmur3_32(name.as_bytes(), 0)
+}
+
+/// The hash as it's stored in the image, reinterpreted as a float. Hashes that would be
+/// denormal, infinite or NaN get one exponent bit flipped
+pub fn hash_to_float(hash: u32) -> f32 {
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        f32::from_bits(hash ^ (1 << 23))
+    } else {
+        f32::from_bits(hash)
+    }
+}
+
+/// One ID matte, like all objects or all materials
+pub struct Matte {
+    /// name of the layer in the EXR
+    pub layer: String,
+    /// names for the IDs, `names[0]` is ID 1
+    pub names: Vec<String>,
+    /// weights per ID seen in every pixel, x first like the film
+    coverage: Vec<Vec<(u32, f32)>>,
+}
+
+impl Matte {
+    pub fn new(layer: &str, names: Vec<String>, pixels: usize) -> Self {
+        Self {
+            layer: layer.to_string(),
+            names,
+            coverage: vec![Vec::new(); pixels],
+        }
+    }
+
+    /// Add a sample with ID `id` (0 for the background, which isn't recorded)
+    pub fn add(&mut self, pixel: usize, id: u32, weight: f32) {
+        if id == 0 {
+            return;
+        }
+
+        let coverage = &mut self.coverage[pixel];
+        match coverage.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, w)) => *w += weight,
+            None => coverage.push((id, weight)),
+        }
+    }
+
+    /// The hashes and coverages of the `RANKS` IDs that cover most of a pixel, with `weight`
+    /// the total weight of all its samples. Padded with zeros
+    pub fn ranked(&self, pixel: usize, weight: f32) -> [(f32, f32); RANKS] {
+        let mut ranked = [(0.0, 0.0); RANKS];
+        if weight == 0.0 {
+            return ranked;
+        }
+
+        // different IDs with the same name end up as one
+        let mut coverage: Vec<(u32, f32)> = Vec::new();
+        for &(id, w) in &self.coverage[pixel] {
+            let hash = hash_name(&self.names[id as usize - 1]);
+            match coverage.iter_mut().find(|(h, _)| *h == hash) {
+                Some((_, total)) => *total += w,
+                None => coverage.push((hash, w)),
+            }
+        }
+        coverage.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (rank, &(hash, w)) in ranked.iter_mut().zip(&coverage) {
+            *rank = (hash_to_float(hash), w / weight);
+        }
+
+        ranked
+    }
+
+    /// A matte of just the pixels `pixels`, in that order
+    pub fn crop(&self, pixels: &[usize]) -> Self {
+        Self {
+            layer: self.layer.clone(),
+            names: self.names.clone(),
+            coverage: pixels.iter().map(|&i| self.coverage[i].clone()).collect(),
+        }
+    }
+
+    /// Put a matte from `crop` back, `pixels` are the pixels it was cropped to
+    pub fn paste(&mut self, cropped: &Matte, pixels: &[usize]) {
+        for (&i, coverage) in pixels.iter().zip(&cropped.coverage) {
+            self.coverage[i].clone_from(coverage);
+        }
+    }
+
+    /// The coverage of every pixel for a checkpoint
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for coverage in &self.coverage {
+            write_u32(out, coverage.len() as u32)?;
+            for &(id, weight) in coverage {
+                write_u32(out, id)?;
+                write_f32s(out, [weight])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read back what `write` wrote
+    pub fn read(&mut self, input: &mut impl Read) -> io::Result<()> {
+        for coverage in &mut self.coverage {
+            let len = read_u32(input)?;
+            coverage.clear();
+            for _ in 0..len {
+                let id = read_u32(input)?;
+                let mut weight = 0.0;
+                read_f32s(input, [&mut weight])?;
+                coverage.push((id, weight));
+            }
+        }
+        Ok(())
+    }
+
+    /// Header attributes the Cryptomatte spec asks for, keyed by a short hash of the layer name
+    pub fn metadata(&self) -> HashMap<String, String> {
+        let key = &format!("{:08x}", hash_name(&self.layer))[..7];
+        let prefix = format!("cryptomatte/{}/", key);
+
+        HashMap::from([
+            (prefix.clone() + "name", self.layer.clone()),
+            (prefix.clone() + "hash", "MurmurHash3_32".to_string()),
+            (
+                prefix.clone() + "conversion",
+                "uint32_to_float32".to_string(),
+            ),
+            (prefix + "manifest", self.manifest()),
+        ])
+    }
+
+    /// JSON object from every name to its hash in hex
+    fn manifest(&self) -> String {
+        let escape = |name: &str| {
+            let mut out = String::new();
+            for c in name.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+            out
+        };
+
+        // sorted and without duplicate names
+        let names: BTreeSet<&String> = self.names.iter().collect();
+        let entries: Vec<String> = names
+            .into_iter()
+            .map(|name| {
+                let hash = hash_to_float(hash_name(name)).to_bits();
+                format!("\"{}\":\"{:08x}\"", escape(name), hash)
+            })
+            .collect();
+
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+/// Object and material mattes, filled in from the IDs in the AOVs
+pub struct Cryptomatte {
+    pub objects: Matte,
+    pub materials: Matte,
+}
+
+impl Cryptomatte {
+    pub fn new(object_names: Vec<String>, material_names: Vec<String>, pixels: usize) -> Self {
+        Self {
+            objects: Matte::new("CryptoObject", object_names, pixels),
+            materials: Matte::new("CryptoMaterial", material_names, pixels),
+        }
+    }
+
+    pub fn add(&mut self, pixel: usize, aovs: &Aovs, weight: f32) {
+        self.objects.add(pixel, aovs.object_id, weight);
+        self.materials.add(pixel, aovs.material_id, weight);
+    }
+
+    pub fn crop(&self, pixels: &[usize]) -> Self {
+        Self {
+            objects: self.objects.crop(pixels),
+            materials: self.materials.crop(pixels),
+        }
+    }
+
+    pub fn paste(&mut self, cropped: &Cryptomatte, pixels: &[usize]) {
+        self.objects.paste(&cropped.objects, pixels);
+        self.materials.paste(&cropped.materials, pixels);
+    }
+}
@@ -0,0 +1,137 @@
+use nalgebra::DMatrix;
+use rayon::prelude::*;
+
+use crate::color::luminance;
+use crate::film::Film;
+use crate::{Color, Vector3f};
+
+/// Edge-avoiding À-trous wavelet filter, "Edge-Avoiding À-Trous Wavelet Transform for fast
+/// Global Illumination Filtering" (Dammertz et al. 2010). Blurs the image with a growing
+/// kernel while the first-hit albedo, normal and depth keep it from blurring across edges
+#[derive(Clone, Copy, Debug)]
+pub struct Denoiser {
+    /// passes of the filter, every one reaches twice as far as the one before
+    pub iterations: u32,
+    /// how different neighbouring colors can be before they stop counting, halves every pass
+    pub sigma_color: f32,
+    pub sigma_normal: f32,
+    /// relative to the depth of the pixel
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 0.5,
+            sigma_normal: 0.3,
+            sigma_depth: 0.05,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+/// First-hit features of a pixel that guide the filter
+struct Features {
+    albedo: Color,
+    normal: Vector3f,
+    depth: f32,
+}
+
+/// B3 spline
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+impl Denoiser {
+    /// The denoised image of a film that records AOVs, the film itself isn't touched
+    pub fn apply(&self, film: &Film) -> DMatrix<Color> {
+        let (width, height) = (film.width, film.height);
+
+        let mut features = Vec::with_capacity(width * height);
+        let mut image = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let aovs = film
+                    .aovs(x, y)
+                    .expect("denoising needs a film that records AOVs");
+
+                // filter the lighting without the surface color, so textures stay sharp
+                let albedo = aovs.albedo.map(|a| a.max(0.01));
+                image.push(film.raw_pixel(x, y).component_div(&albedo));
+
+                features.push(Features {
+                    albedo: aovs.albedo,
+                    normal: aovs.normal,
+                    depth: aovs.depth,
+                });
+            }
+        }
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let sigma_color = self.sigma_color / (1 << iteration) as f32;
+
+            image = (0..width * height)
+                .into_par_iter()
+                .map(|i| {
+                    let (x, y) = ((i % width) as isize, (i / width) as isize);
+                    let center = &features[i];
+                    let center_color = compress(image[i]);
+
+                    let mut sum = Color::zeros();
+                    let mut total = 0.0;
+
+                    for (ky, wy) in KERNEL.iter().enumerate() {
+                        for (kx, wx) in KERNEL.iter().enumerate() {
+                            let qx = x + (kx as isize - 2) * step;
+                            let qy = y + (ky as isize - 2) * step;
+                            if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                                continue;
+                            }
+
+                            let j = qy as usize * width + qx as usize;
+                            let other = &features[j];
+
+                            let color = (compress(image[j]) - center_color).norm_squared();
+                            let normal = (other.normal - center.normal).norm_squared();
+                            let albedo = (other.albedo - center.albedo).norm_squared();
+
+                            let weight = wx
+                                * wy
+                                * (-color / (sigma_color * sigma_color)).exp()
+                                * (-normal / (self.sigma_normal * self.sigma_normal)).exp()
+                                * (-albedo / (self.sigma_albedo * self.sigma_albedo)).exp()
+                                * self.depth_weight(center.depth, other.depth, step as f32);
+
+                            sum += image[j] * weight;
+                            total += weight;
+                        }
+                    }
+
+                    // the center always counts, so the total can't be zero
+                    sum / total
+                })
+                .collect();
+        }
+
+        DMatrix::from_fn(width, height, |x, y| {
+            let albedo = features[y * width + x].albedo.map(|a| a.max(0.01));
+            image[y * width + x].component_mul(&albedo)
+        })
+    }
+
+    fn depth_weight(&self, center: f32, other: f32, step: f32) -> f32 {
+        // the background is infinitely far away and only matches itself
+        if center.is_infinite() || other.is_infinite() {
+            return if center == other { 1.0 } else { 0.0 };
+        }
+
+        // neighbours further out are allowed to be further away
+        (-(center - other).abs() / (self.sigma_depth * center.max(1e-3) * step)).exp()
+    }
+}
+
+/// Squash HDR colors so bright pixels don't get kept apart from everything around them
+fn compress(color: Color) -> Color {
+    color / (1.0 + luminance(color).max(0.0))
+}
@@ -0,0 +1,198 @@
+use crate::Vector2f;
+
+/// Piecewise constant distribution over `0..n`, picks indices proportionally to their weight
+#[derive(Default)]
+pub struct Distribution1D {
+    /// running sum of the weights, the last element is the total
+    cdf: Vec<f32>,
+}
+
+impl Distribution1D {
+    pub fn new(weights: impl IntoIterator<Item = f32>) -> Self {
+        let mut sum = 0.0;
+        let cdf = weights
+            .into_iter()
+            .map(|w| {
+                sum += w.max(0.0);
+                sum
+            })
+            .collect();
+
+        Self { cdf }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cdf.len()
+    }
+
+    pub fn total(&self) -> f32 {
+        self.cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Nothing can be sampled if all weights are zero
+    pub fn is_empty(&self) -> bool {
+        self.total() <= 0.0
+    }
+
+    /// Pick an index with a uniform random number `u` in [0, 1), returns it with its probability
+    pub fn sample(&self, u: f32) -> (usize, f32) {
+        let target = u * self.total();
+        let mut index = self
+            .cdf
+            .partition_point(|&c| c <= target)
+            .min(self.len() - 1);
+
+        // rounding can push the target past the end onto trailing zero weights
+        while index > 0 && self.pdf(index) == 0.0 {
+            index -= 1;
+        }
+
+        (index, self.pdf(index))
+    }
+
+    /// Like `sample` but treats the distribution as a step function over [0, 1), returns the
+    /// position, the density there and the index of the step it's on
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let (index, prob) = self.sample(u);
+
+        let before = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        let offset = (u * self.total() - before) / (self.cdf[index] - before);
+        let offset = offset.clamp(0.0, 1.0 - f32::EPSILON);
+
+        // the division can still round the top of the last step up to 1
+        let n = self.len() as f32;
+        let x = ((index as f32 + offset) / n).min(1.0 - f32::EPSILON / 2.0);
+
+        (x, prob * n, index)
+    }
+
+    /// Probability of picking `index`
+    pub fn pdf(&self, index: usize) -> f32 {
+        let before = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        (self.cdf[index] - before) / self.total()
+    }
+
+    /// Density of `sample_continuous` returning `x`
+    pub fn pdf_continuous(&self, x: f32) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let n = self.len();
+        let index = ((x * n as f32) as usize).min(n - 1);
+        self.pdf(index) * n as f32
+    }
+}
+
+/// Piecewise constant distribution over the unit square, first picks a row from the marginal
+/// distribution and then a column within that row
+#[derive(Default)]
+pub struct Distribution2D {
+    /// one distribution over the columns for each row
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `weights(column, row)` for every cell of a `columns` by `rows` grid
+    pub fn new(columns: usize, rows: usize, weights: impl Fn(usize, usize) -> f32) -> Self {
+        let conditional: Vec<_> = (0..rows)
+            .map(|row| Distribution1D::new((0..columns).map(|column| weights(column, row))))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(Distribution1D::total));
+
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marginal.is_empty()
+    }
+
+    /// Pick a point in [0, 1)² with two uniform random numbers, returns it with its density
+    pub fn sample(&self, u1: f32, u2: f32) -> (Vector2f, f32) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u2);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u1);
+
+        (Vector2f::new(x, y), pdf_x * pdf_y)
+    }
+
+    /// Density of `sample` returning `point`
+    pub fn pdf(&self, point: Vector2f) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let rows = self.conditional.len();
+        let row = ((point.y * rows as f32) as usize).min(rows - 1);
+
+        self.marginal.pdf_continuous(point.y) * self.conditional[row].pdf_continuous(point.x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Midpoints of `n` equal steps through [0, 1)
+    fn grid(n: usize) -> impl Iterator<Item = f32> + Clone {
+        (0..n).map(move |i| (i as f32 + 0.5) / n as f32)
+    }
+
+    #[test]
+    fn sample_1d_matches_pdf() {
+        let distribution = Distribution1D::new([1.0, 0.0, 3.0, 4.0, 0.0]);
+        let total: f32 = (0..distribution.len()).map(|i| distribution.pdf(i)).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+
+        let n = 10000;
+        let mut counts = [0; 5];
+        for u in grid(n) {
+            let (index, pdf) = distribution.sample(u);
+            assert!(pdf > 0.0);
+            assert_eq!(pdf, distribution.pdf(index));
+            counts[index] += 1;
+        }
+        for (index, &count) in counts.iter().enumerate() {
+            assert!((count as f32 / n as f32 - distribution.pdf(index)).abs() < 1e-3);
+        }
+
+        // rounding right below 1 can't pick the weightless last index
+        assert_eq!(distribution.sample(1.0 - f32::EPSILON / 2.0).0, 3);
+    }
+
+    #[test]
+    fn sample_continuous_matches_pdf() {
+        let distribution = Distribution1D::new([2.0, 0.0, 1.0, 5.0]);
+
+        for u in grid(1000).chain([0.0, 1.0 - f32::EPSILON / 2.0]) {
+            let (x, pdf, index) = distribution.sample_continuous(u);
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(index, (x * 4.0) as usize);
+            assert!((pdf - distribution.pdf_continuous(x)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sample_2d_matches_pdf() {
+        // every third row is empty
+        let distribution = Distribution2D::new(5, 6, |x, y| (x + 1) as f32 * (y % 3) as f32);
+
+        for u1 in grid(50) {
+            for u2 in grid(50) {
+                let (point, pdf) = distribution.sample(u1, u2);
+                assert!(pdf > 0.0);
+                assert!((pdf - distribution.pdf(point)).abs() < 1e-4 * pdf);
+            }
+        }
+
+        // a density over the unit square
+        let integral: f32 = grid(5)
+            .flat_map(|x| grid(6).map(move |y| Vector2f::new(x, y)))
+            .map(|point| distribution.pdf(point) / 30.0)
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-5);
+    }
+}
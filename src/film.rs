@@ -0,0 +1,294 @@
+use std::io::{Read, Write};
+
+use anyhow::bail;
+use nalgebra::DMatrix;
+
+use crate::aov::Aovs;
+use crate::checkpoint::{read_f32s, read_u32, write_f32s, write_u32};
+use crate::color::luminance;
+use crate::cryptomatte::Cryptomatte;
+use crate::denoise::Denoiser;
+use crate::tile::Tile;
+use crate::Color;
+
+/// One sample of a pixel, with the weight the pixel filter gave it
+#[derive(Clone, Debug, Default)]
+pub struct PixelSample {
+    pub radiance: Color,
+    pub weight: f32,
+    /// only there when the film records AOVs
+    pub aovs: Option<Box<Aovs>>,
+}
+
+/// Accumulates samples for every pixel of an image
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    /// passes over the image, with adaptive sampling not every pixel got a sample in every one
+    pub samples: u32,
+
+    /// weighted sums of the samples
+    accum: DMatrix<Color>,
+    weights: DMatrix<f32>,
+    /// samples taken per pixel, and the sums of their (squashed) luminance and its square
+    counts: DMatrix<u32>,
+    luminance: DMatrix<f32>,
+    luminance_sq: DMatrix<f32>,
+    /// write the sample counts out as an AOV
+    sample_counts: bool,
+    aovs: Option<DMatrix<Aovs>>,
+    cryptomatte: Option<Cryptomatte>,
+    /// replaces the averaged samples until more are added
+    denoised: Option<DMatrix<Color>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            samples: 0,
+            accum: DMatrix::zeros(width, height),
+            weights: DMatrix::zeros(width, height),
+            counts: DMatrix::zeros(width, height),
+            luminance: DMatrix::zeros(width, height),
+            luminance_sq: DMatrix::zeros(width, height),
+            sample_counts: false,
+            aovs: None,
+            cryptomatte: None,
+            denoised: None,
+        }
+    }
+
+    /// A film that also records AOVs, the samples need to have them
+    pub fn with_aovs(width: usize, height: usize) -> Self {
+        Self {
+            aovs: Some(DMatrix::from_element(width, height, Aovs::default())),
+            ..Self::new(width, height)
+        }
+    }
+
+    /// Also record ID mattes, the samples need to have AOVs for this too
+    pub fn with_cryptomatte(mut self, cryptomatte: Cryptomatte) -> Self {
+        self.cryptomatte = Some(cryptomatte);
+        self
+    }
+
+    /// Also write how many samples every pixel got, for seeing where adaptive sampling spent them
+    pub fn with_sample_counts(mut self) -> Self {
+        self.sample_counts = true;
+        self
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    pub fn cryptomatte(&self) -> Option<&Cryptomatte> {
+        self.cryptomatte.as_ref()
+    }
+
+    pub fn has_sample_counts(&self) -> bool {
+        self.sample_counts
+    }
+
+    /// Add one sample for every pixel, in the same order as the matrices (x first)
+    pub fn add_pass(&mut self, pass: &[PixelSample]) {
+        let pixels: Vec<usize> = (0..pass.len()).collect();
+        self.add_samples(&pixels, pass);
+    }
+
+    /// Add one sample for only some pixels, `samples[i]` belongs to pixel `pixels[i]`.
+    /// Counts as a pass even though the other pixels don't get anything
+    pub fn add_samples(&mut self, pixels: &[usize], samples: &[PixelSample]) {
+        for (&i, sample) in pixels.iter().zip(samples) {
+            self.accum[i] += sample.radiance * sample.weight;
+            self.weights[i] += sample.weight;
+
+            // how bright the sample looks on a display, so noise in highlights doesn't dominate
+            let l = luminance(sample.radiance).max(0.0);
+            let l = l / (1.0 + l);
+            self.luminance[i] += l;
+            self.luminance_sq[i] += l * l;
+
+            if let (Some(aovs), Some(sample_aovs)) = (&mut self.aovs, &sample.aovs) {
+                aovs[i].accumulate(sample_aovs, sample.weight, self.counts[i] == 0);
+            }
+            if let (Some(cryptomatte), Some(sample_aovs)) = (&mut self.cryptomatte, &sample.aovs) {
+                cryptomatte.add(i, sample_aovs, sample.weight);
+            }
+
+            self.counts[i] += 1;
+        }
+        self.samples += 1;
+        self.denoised = None;
+    }
+
+    /// Number of samples a pixel got, by its index in the matrices
+    pub fn sample_count(&self, pixel: usize) -> u32 {
+        self.counts[pixel]
+    }
+
+    /// Standard error of a pixel's squashed luminance (0..1), infinite with less than two samples
+    pub fn error(&self, pixel: usize) -> f32 {
+        let n = self.counts[pixel] as f32;
+        if n < 2.0 {
+            return f32::INFINITY;
+        }
+
+        let mean = self.luminance[pixel] / n;
+        let variance = (self.luminance_sq[pixel] / n - mean * mean).max(0.0) * n / (n - 1.0);
+        (variance / n).sqrt()
+    }
+
+    /// Denoise the image, `pixel` gives the denoised colors until the next pass is added.
+    /// Needs the film to record AOVs
+    pub fn denoise(&mut self, denoiser: &Denoiser) {
+        self.denoised = Some(denoiser.apply(self));
+    }
+
+    /// The final color of a pixel, denoised if the film was
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        match &self.denoised {
+            Some(denoised) => denoised[(x, y)],
+            None => self.raw_pixel(x, y),
+        }
+    }
+
+    /// Weighted average of all samples taken for a pixel
+    pub fn raw_pixel(&self, x: usize, y: usize) -> Color {
+        let weight = self.weights[(x, y)];
+        if weight == 0.0 {
+            return Color::zeros();
+        }
+
+        self.accum[(x, y)] / weight
+    }
+
+    /// Total filter weight of all samples of a pixel
+    pub fn weight(&self, x: usize, y: usize) -> f32 {
+        self.weights[(x, y)]
+    }
+
+    /// Averaged AOVs of a pixel, None if the film doesn't record them
+    pub fn aovs(&self, x: usize, y: usize) -> Option<Aovs> {
+        let aovs = self.aovs.as_ref()?;
+        Some(aovs[(x, y)].resolve(self.weights[(x, y)]))
+    }
+
+    /// A film of just the pixels in `tile`, with everything they accumulated so far. Samples
+    /// added to it go back into this film with `paste`
+    pub fn crop(&self, tile: &Tile) -> Film {
+        let pixels = tile.pixels(self.width);
+        let crop = |matrix: &DMatrix<f32>| {
+            DMatrix::from_fn(tile.width, tile.height, |x, y| {
+                matrix[pixels[y * tile.width + x]]
+            })
+        };
+
+        Film {
+            width: tile.width,
+            height: tile.height,
+            // passes the tile had, rather than the whole image
+            samples: pixels.iter().map(|&i| self.counts[i]).max().unwrap_or(0),
+            accum: DMatrix::from_fn(tile.width, tile.height, |x, y| {
+                self.accum[pixels[y * tile.width + x]]
+            }),
+            weights: crop(&self.weights),
+            counts: DMatrix::from_fn(tile.width, tile.height, |x, y| {
+                self.counts[pixels[y * tile.width + x]]
+            }),
+            luminance: crop(&self.luminance),
+            luminance_sq: crop(&self.luminance_sq),
+            sample_counts: self.sample_counts,
+            aovs: self.aovs.as_ref().map(|aovs| {
+                DMatrix::from_fn(tile.width, tile.height, |x, y| {
+                    aovs[pixels[y * tile.width + x]]
+                })
+            }),
+            cryptomatte: self.cryptomatte.as_ref().map(|c| c.crop(&pixels)),
+            denoised: None,
+        }
+    }
+
+    /// Put a film from `crop` back where it came from
+    pub fn paste(&mut self, cropped: &Film, tile: &Tile) {
+        for (j, i) in tile.pixels(self.width).into_iter().enumerate() {
+            self.accum[i] = cropped.accum[j];
+            self.weights[i] = cropped.weights[j];
+            self.counts[i] = cropped.counts[j];
+            self.luminance[i] = cropped.luminance[j];
+            self.luminance_sq[i] = cropped.luminance_sq[j];
+
+            if let (Some(aovs), Some(cropped_aovs)) = (&mut self.aovs, &cropped.aovs) {
+                aovs[i] = cropped_aovs[j];
+            }
+        }
+        if let (Some(cryptomatte), Some(cropped)) = (&mut self.cryptomatte, &cropped.cryptomatte) {
+            cryptomatte.paste(cropped, &tile.pixels(self.width));
+        }
+
+        self.samples = self.samples.max(cropped.samples);
+        self.denoised = None;
+    }
+
+    /// Everything accumulated so far, for a checkpoint. The denoised image isn't kept
+    pub fn write_state(&self, out: &mut impl Write) -> anyhow::Result<()> {
+        write_u32(out, self.samples)?;
+        write_u32(out, self.aovs.is_some() as u32)?;
+        write_u32(out, self.cryptomatte.is_some() as u32)?;
+
+        write_f32s(out, self.accum.iter().flat_map(|c| c.iter().copied()))?;
+        write_f32s(out, self.weights.iter().copied())?;
+        for &count in self.counts.iter() {
+            write_u32(out, count)?;
+        }
+        write_f32s(out, self.luminance.iter().copied())?;
+        write_f32s(out, self.luminance_sq.iter().copied())?;
+
+        if let Some(aovs) = &self.aovs {
+            for aov in aovs.iter() {
+                aov.write(out)?;
+            }
+        }
+        if let Some(cryptomatte) = &self.cryptomatte {
+            cryptomatte.objects.write(out)?;
+            cryptomatte.materials.write(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read back what `write_state` wrote, into a film of the same size that records the same
+    /// AOVs
+    pub fn read_state(&mut self, input: &mut impl Read) -> anyhow::Result<()> {
+        let samples = read_u32(input)?;
+        let has_aovs = read_u32(input)? != 0;
+        let has_cryptomatte = read_u32(input)? != 0;
+        if has_aovs != self.aovs.is_some() || has_cryptomatte != self.cryptomatte.is_some() {
+            bail!("the checkpoint was rendered with different AOVs");
+        }
+
+        self.samples = samples;
+        read_f32s(input, self.accum.iter_mut().flat_map(|c| c.iter_mut()))?;
+        read_f32s(input, self.weights.iter_mut())?;
+        for count in self.counts.iter_mut() {
+            *count = read_u32(input)?;
+        }
+        read_f32s(input, self.luminance.iter_mut())?;
+        read_f32s(input, self.luminance_sq.iter_mut())?;
+
+        if let Some(aovs) = &mut self.aovs {
+            for aov in aovs.iter_mut() {
+                aov.read(input)?;
+            }
+        }
+        if let Some(cryptomatte) = &mut self.cryptomatte {
+            cryptomatte.objects.read(input)?;
+            cryptomatte.materials.read(input)?;
+        }
+        self.denoised = None;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::distribution::Distribution1D;
+use crate::Vector2f;
+
+/// Pixel reconstruction filters, all of them separable into the same curve along x and y
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    #[default]
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3, sharper but can ring around edges
+    Mitchell,
+    BlackmanHarris,
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "blackman-harris" => Ok(FilterKind::BlackmanHarris),
+            _ => Err(format!(
+                "unknown filter '{}', expected box, tent, gaussian, mitchell or blackman-harris",
+                s
+            )),
+        }
+    }
+}
+
+impl FilterKind {
+    /// Radius in pixels the filter is usually used with
+    pub fn default_radius(self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::BlackmanHarris => 2.0,
+        }
+    }
+}
+
+/// A filter with a radius, ready to be sampled.
+///
+/// Samples are placed proportionally to the filter (filter importance sampling), so every
+/// sample only ever counts towards its own pixel. Filters with negative lobes get samples with
+/// negative weights there
+pub struct Filter {
+    pub kind: FilterKind,
+    /// in pixels, in both directions from the pixel center
+    pub radius: f32,
+
+    /// over the absolute value of the curve on [-radius, radius]
+    distribution: Distribution1D,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        let kind = FilterKind::default();
+        Self::new(kind, kind.default_radius())
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: f32) -> Self {
+        let mut filter = Self {
+            kind,
+            radius,
+            distribution: Distribution1D::default(),
+        };
+
+        // finely tabulated so the pdf follows the curve closely and the weights barely vary
+        let bins = (radius * 64.0).ceil().max(16.0) as usize;
+        filter.distribution = Distribution1D::new((0..bins).map(|i| {
+            let x = ((i as f32 + 0.5) / bins as f32 * 2.0 - 1.0) * radius;
+            filter.evaluate(x).abs()
+        }));
+
+        filter
+    }
+
+    /// The filter curve at offset `x` from the pixel center
+    fn evaluate(&self, x: f32) -> f32 {
+        let r = self.radius;
+        if x.abs() > r {
+            return 0.0;
+        }
+
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => r - x.abs(),
+            FilterKind::Gaussian => {
+                // falls to zero at the radius instead of being cut off
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(r)
+            }
+            FilterKind::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x = (2.0 * x / r).abs();
+
+                if x <= 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                }
+            }
+            FilterKind::BlackmanHarris => {
+                let t = (x + r) / (2.0 * r);
+                0.35875 - 0.48829 * (2.0 * PI * t).cos() + 0.14128 * (4.0 * PI * t).cos()
+                    - 0.01168 * (6.0 * PI * t).cos()
+            }
+        }
+    }
+
+    /// Offset along one axis and the weight of the sample there
+    fn sample_1d(&self, u: f32) -> (f32, f32) {
+        let (t, density, _) = self.distribution.sample_continuous(u);
+        let x = (t * 2.0 - 1.0) * self.radius;
+        let pdf = density / (2.0 * self.radius);
+
+        (x, self.evaluate(x) / pdf)
+    }
+
+    /// Offset from the pixel center for a sample, and the weight it gets when averaging
+    pub fn sample(&self, u: Vector2f) -> (Vector2f, f32) {
+        let (x, weight_x) = self.sample_1d(u.x);
+        let (y, weight_y) = self.sample_1d(u.y);
+
+        (Vector2f::new(x, y), weight_x * weight_y)
+    }
+}
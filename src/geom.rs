@@ -6,17 +6,21 @@ use bvh::aabb::{Aabb, Bounded};
 use bvh::bounding_hierarchy::BHShape;
 use bvh::bvh::Bvh;
 
-use crate::{Affine, Matrix4d, Matrix4f, Point3d, Point3f, Quaternion, Ray, Vector3d, Vector3f};
+use crate::{Affine, Color, Matrix4d, Matrix4f, Point3d, Point3f, Quaternion, Ray, Vector3d, Vector3f};
 
 pub struct Object {
+    pub name: String,
     pub transform: Transform,
     pub mesh: Mesh,
     pub material: Material,
+    /// objects with the same material name share a material ID
+    pub material_name: String,
 }
 
 impl Debug for Object {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Object")
+            .field("name", &self.name)
             .field("transform", &self.transform)
             .finish()
     }
@@ -161,8 +165,65 @@ impl BHShape<f32, 3> for BVHTriangle {
 
 #[derive(Debug, Clone, Copy)]
 pub enum Material {
-    Diffuse(f32),
+    Diffuse(Color),
     Glossy,
+    Dielectric {
+        ior: f32,
+        tint: Color,
+    },
+    /// rough metal, `eta` and `k` are the real and imaginary parts of its index of refraction
+    Conductor {
+        roughness: f32,
+        eta: Color,
+        k: Color,
+        tint: Color,
+    },
+    /// light source, emits `color * strength` and doesn't reflect anything
+    Emissive {
+        color: Color,
+        strength: f32,
+        two_sided: bool,
+    },
+}
+
+impl Material {
+    /// Whether light can pass through the surface, these are not backface culled
+    pub fn is_transmissive(&self) -> bool {
+        matches!(self, Material::Dielectric { .. })
+    }
+
+    /// Radiance emitted by the surface, `backface` is whether it is seen from behind
+    pub fn emission(&self, backface: bool) -> Color {
+        match self {
+            Material::Emissive {
+                color,
+                strength,
+                two_sided,
+            } if *two_sided || !backface => color * *strength,
+            _ => Color::zeros(),
+        }
+    }
+
+    /// Base color of the surface, what it reflects looking straight at it
+    pub fn albedo(&self) -> Color {
+        match self {
+            Material::Diffuse(albedo) => *albedo,
+            Material::Glossy => Color::repeat(1.0),
+            Material::Dielectric { tint, .. } => *tint,
+            Material::Conductor { eta, k, tint, .. } => {
+                let reflectance = |eta: f32, k: f32| {
+                    ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k)
+                };
+                Color::new(
+                    reflectance(eta.x, k.x),
+                    reflectance(eta.y, k.y),
+                    reflectance(eta.z, k.z),
+                )
+                .component_mul(tint)
+            }
+            Material::Emissive { .. } => Color::zeros(),
+        }
+    }
 }
 
 pub struct BvhScene {
@@ -170,6 +231,9 @@ pub struct BvhScene {
     pub triangles: Vec<BVHTriangle>,
     pub normals: Vec<(Vector3f, Vector3f, Vector3f)>,
     pub materials: Vec<Material>,
+    /// AOV object and material IDs of every triangle
+    pub object_ids: Vec<u32>,
+    pub material_ids: Vec<u32>,
 }
 
 // copy pasted from https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
@@ -217,6 +281,8 @@ impl BvhScene {
         mut triangles: Vec<BVHTriangle>,
         normals: Vec<(Vector3f, Vector3f, Vector3f)>,
         materials: Vec<Material>,
+        object_ids: Vec<u32>,
+        material_ids: Vec<u32>,
     ) -> Self {
         let bvh = Bvh::build(&mut triangles);
 
@@ -225,6 +291,8 @@ impl BvhScene {
             triangles,
             normals,
             materials,
+            object_ids,
+            material_ids,
         }
     }
 
@@ -251,6 +319,16 @@ impl BvhScene {
 
         hit_idx.map(|idx| (min_t, idx))
     }
+
+    /// Whether anything is hit closer than `max_dist` along the ray
+    pub fn occluded(&self, ray: &Ray, max_dist: f32) -> bool {
+        let hits = self.bvh.traverse(ray, &self.triangles);
+
+        hits.into_iter().any(|triangle| {
+            ray_triangle_intersection(ray, triangle.a, triangle.b, triangle.c)
+                .is_some_and(|t| t < max_dist)
+        })
+    }
 }
 
 fn inv_sqrt(x: f32) -> f32 {
@@ -0,0 +1,39 @@
+//! A CPU path tracer.
+//!
+//! Build a [`Scene`] either by hand or with [`scenefile::load_scene`], call
+//! [`Scene::build_bvh`] and hand it to [`render`] to get a [`Film`] back.
+
+pub mod aov;
+mod bsdf;
+pub mod camera;
+pub mod checkpoint;
+pub mod color;
+pub mod cryptomatte;
+pub mod denoise;
+mod distribution;
+pub mod film;
+pub mod filter;
+pub mod geom;
+pub mod light;
+pub mod objfile;
+pub mod output;
+pub mod render;
+mod rng;
+pub mod sampler;
+pub mod scene;
+pub mod scenefile;
+pub mod texture;
+pub mod tile;
+mod types;
+
+use std::f64::consts::PI;
+
+pub use color::Color;
+pub use film::Film;
+pub use render::{render, render_from, RenderSettings};
+pub use scene::Scene;
+pub use types::*;
+
+pub fn rad(deg: f64) -> f64 {
+    deg * PI / 180.0
+}
@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use nalgebra::DMatrix;
+
+use crate::color::luminance;
+use crate::distribution::{Distribution1D, Distribution2D};
+use crate::geom::{BvhScene, Material};
+use crate::sampler::sample_disk;
+use crate::{Color, Matrix4f, Point3f, Vector2f, Vector3f};
+
+/// An emissive triangle, in the same (camera) space as the BVH
+pub struct AreaLight {
+    /// index of the triangle in the BVH
+    pub triangle: usize,
+
+    pub a: Point3f,
+    pub b: Point3f,
+    pub c: Point3f,
+
+    /// geometric normal, on the side the emitting face is on
+    pub normal: Vector3f,
+    pub area: f32,
+
+    pub emission: Color,
+    pub two_sided: bool,
+}
+
+impl AreaLight {
+    /// Radiance leaving the light towards `direction`
+    pub fn radiance(&self, direction: Vector3f) -> Color {
+        if self.two_sided || direction.dot(&self.normal) > 0.0 {
+            self.emission
+        } else {
+            Color::zeros()
+        }
+    }
+
+    fn power(&self) -> f32 {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        luminance(self.emission) * self.area * sides * PI
+    }
+
+    /// Uniformly pick a point on the triangle as seen from `from`
+    fn sample(&self, from: Point3f, u: Vector2f) -> Option<LightSample> {
+        let su = u.x.sqrt();
+        let (b0, b1) = (1.0 - su, u.y * su);
+        let point = self.a + (self.b - self.a) * b1 + (self.c - self.a) * (1.0 - b0 - b1);
+
+        let to_light = point - from;
+        let distance = to_light.norm();
+        if distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+
+        let cos_light = direction.dot(&self.normal).abs();
+        if cos_light <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.radiance(-direction),
+            // convert the uniform area pdf to solid angle
+            pdf: distance * distance / (self.area * cos_light),
+            delta: false,
+            light: None,
+        })
+    }
+}
+
+/// How the light from point and spot lights fades with distance
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Falloff {
+    /// physically correct
+    #[default]
+    InverseSquare,
+    Linear,
+    Constant,
+}
+
+impl Falloff {
+    fn attenuation(self, distance: f32) -> f32 {
+        match self {
+            Falloff::InverseSquare => 1.0 / (distance * distance),
+            Falloff::Linear => 1.0 / distance,
+            Falloff::Constant => 1.0,
+        }
+    }
+}
+
+/// Lights that aren't made out of emissive triangles, positions and directions are in world
+/// space until the scene is built. The colors already include the strength of the light
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Point {
+        position: Point3f,
+        intensity: Color,
+        falloff: Falloff,
+    },
+    /// point light shining into a cone around `direction`, smoothly fading out between the
+    /// inner and outer angle
+    Spot {
+        position: Point3f,
+        direction: Vector3f,
+        intensity: Color,
+        falloff: Falloff,
+        cos_outer: f32,
+        cos_inner: f32,
+    },
+    /// infinitely far away light like the sun, `direction` is the way the light travels.
+    /// Arrives from a cone of directions with an opening of `acos(cos_max)`, or just one
+    /// direction if `cos_max` is one
+    Directional {
+        direction: Vector3f,
+        irradiance: Color,
+        cos_max: f32,
+    },
+    Disk {
+        center: Point3f,
+        normal: Vector3f,
+        radius: f32,
+        radiance: Color,
+        two_sided: bool,
+    },
+    /// rectangle spanning `center ± u ± v`, emitting towards `u × v`
+    Rect {
+        center: Point3f,
+        u: Vector3f,
+        v: Vector3f,
+        radiance: Color,
+        two_sided: bool,
+    },
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge0 >= edge1 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Two unit vectors perpendicular to `n` and each other, "Building an Orthonormal Basis,
+/// Revisited" (Duff et al. 2017)
+fn orthonormal_basis(n: Vector3f) -> (Vector3f, Vector3f) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        Vector3f::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vector3f::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+/// Uniformly pick a direction within `acos(cos_max)` of `axis`
+fn sample_cone(axis: Vector3f, cos_max: f32, u: Vector2f) -> Vector3f {
+    let cos_theta = 1.0 - u.x * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+
+    let (t, b) = orthonormal_basis(axis);
+    (t * phi.cos() + b * phi.sin()) * sin_theta + axis * cos_theta
+}
+
+impl Light {
+    /// Move the light into the space `matrix` maps to, which mustn't scale
+    pub fn transformed(&self, matrix: &Matrix4f) -> Self {
+        let point = |p: &Point3f| matrix.transform_point(p);
+        let vector = |v: &Vector3f| matrix.transform_vector(v);
+
+        match *self {
+            Light::Point {
+                position,
+                intensity,
+                falloff,
+            } => Light::Point {
+                position: point(&position),
+                intensity,
+                falloff,
+            },
+            Light::Spot {
+                position,
+                direction,
+                intensity,
+                falloff,
+                cos_outer,
+                cos_inner,
+            } => Light::Spot {
+                position: point(&position),
+                direction: vector(&direction),
+                intensity,
+                falloff,
+                cos_outer,
+                cos_inner,
+            },
+            Light::Directional {
+                direction,
+                irradiance,
+                cos_max,
+            } => Light::Directional {
+                direction: vector(&direction),
+                irradiance,
+                cos_max,
+            },
+            Light::Disk {
+                center,
+                normal,
+                radius,
+                radiance,
+                two_sided,
+            } => Light::Disk {
+                center: point(&center),
+                normal: vector(&normal),
+                radius,
+                radiance,
+                two_sided,
+            },
+            Light::Rect {
+                center,
+                u,
+                v,
+                radiance,
+                two_sided,
+            } => Light::Rect {
+                center: point(&center),
+                u: vector(&u),
+                v: vector(&v),
+                radiance,
+                two_sided,
+            },
+        }
+    }
+
+    /// Rough total power, only used to decide how often to sample the light.
+    /// Directional lights cover a disk as big as the scene
+    fn power(&self, scene_radius: f32) -> f32 {
+        match *self {
+            Light::Point { intensity, .. } => 4.0 * PI * luminance(intensity),
+            Light::Spot {
+                intensity,
+                cos_outer,
+                cos_inner,
+                ..
+            } => 2.0 * PI * (1.0 - (cos_outer + cos_inner) / 2.0) * luminance(intensity),
+            Light::Directional { irradiance, .. } => {
+                PI * scene_radius * scene_radius * luminance(irradiance)
+            }
+            Light::Disk {
+                radiance,
+                two_sided,
+                ..
+            }
+            | Light::Rect {
+                radiance,
+                two_sided,
+                ..
+            } => {
+                let sides = if two_sided { 2.0 } else { 1.0 };
+                luminance(radiance) * self.area() * sides * PI
+            }
+        }
+    }
+
+    fn area(&self) -> f32 {
+        match *self {
+            Light::Disk { radius, .. } => PI * radius * radius,
+            Light::Rect { u, v, .. } => 4.0 * u.cross(&v).norm(),
+            _ => 0.0,
+        }
+    }
+
+    /// Facing direction of disk and rect lights
+    fn normal(&self) -> Vector3f {
+        match *self {
+            Light::Disk { normal, .. } => normal,
+            Light::Rect { u, v, .. } => u.cross(&v).normalize(),
+            _ => Vector3f::zeros(),
+        }
+    }
+
+    /// Radiance seen when looking at the light along `direction`
+    pub fn emitted(&self, direction: Vector3f) -> Color {
+        match *self {
+            Light::Directional {
+                direction: light_direction,
+                irradiance,
+                cos_max,
+            } if cos_max < 1.0 => {
+                if -direction.dot(&light_direction) >= cos_max {
+                    // a uniformly bright disk with angular radius theta gives an irradiance
+                    // of pi * sin^2(theta) * radiance
+                    irradiance / (PI * (1.0 - cos_max * cos_max))
+                } else {
+                    Color::zeros()
+                }
+            }
+            Light::Disk {
+                radiance,
+                two_sided,
+                ..
+            }
+            | Light::Rect {
+                radiance,
+                two_sided,
+                ..
+            } => {
+                if two_sided || direction.dot(&self.normal()) < 0.0 {
+                    radiance
+                } else {
+                    Color::zeros()
+                }
+            }
+            _ => Color::zeros(),
+        }
+    }
+
+    /// Distance along the ray to disk and rect lights
+    fn intersect(&self, origin: Point3f, direction: Vector3f) -> Option<f32> {
+        let center = match *self {
+            Light::Disk { center, .. } | Light::Rect { center, .. } => center,
+            _ => return None,
+        };
+
+        let normal = self.normal();
+        let denom = direction.dot(&normal);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = (center - origin).dot(&normal) / denom;
+        if t <= 0.0 {
+            return None;
+        }
+
+        let local = origin + direction * t - center;
+        let inside = match *self {
+            Light::Disk { radius, .. } => local.norm_squared() <= radius * radius,
+            Light::Rect { u, v, .. } => {
+                local.dot(&u).abs() <= u.norm_squared() && local.dot(&v).abs() <= v.norm_squared()
+            }
+            _ => false,
+        };
+
+        inside.then_some(t)
+    }
+
+    /// Pick a direction towards the light as seen from `from`, the pdf doesn't include the
+    /// probability of picking this light
+    fn sample(&self, from: Point3f, u: Vector2f) -> Option<LightSample> {
+        match *self {
+            Light::Point {
+                position,
+                intensity,
+                falloff,
+            } => {
+                let to_light = position - from;
+                let distance = to_light.norm();
+
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance,
+                    radiance: intensity * falloff.attenuation(distance),
+                    pdf: 1.0,
+                    delta: true,
+                    light: None,
+                })
+            }
+            Light::Spot {
+                position,
+                direction: spot_direction,
+                intensity,
+                falloff,
+                cos_outer,
+                cos_inner,
+            } => {
+                let to_light = position - from;
+                let distance = to_light.norm();
+                let direction = to_light / distance;
+
+                let cos = -direction.dot(&spot_direction);
+                let cone = smoothstep(cos_outer, cos_inner, cos);
+
+                Some(LightSample {
+                    direction,
+                    distance,
+                    radiance: intensity * (cone * falloff.attenuation(distance)),
+                    pdf: 1.0,
+                    delta: true,
+                    light: None,
+                })
+            }
+            Light::Directional {
+                direction,
+                irradiance,
+                cos_max,
+            } => {
+                if cos_max >= 1.0 {
+                    return Some(LightSample {
+                        direction: -direction,
+                        distance: f32::INFINITY,
+                        radiance: irradiance,
+                        pdf: 1.0,
+                        delta: true,
+                        light: None,
+                    });
+                }
+
+                let sampled = sample_cone(-direction, cos_max, u);
+
+                Some(LightSample {
+                    direction: sampled,
+                    distance: f32::INFINITY,
+                    radiance: self.emitted(sampled),
+                    pdf: 1.0 / (2.0 * PI * (1.0 - cos_max)),
+                    delta: false,
+                    light: None,
+                })
+            }
+            Light::Disk { center, radius, .. } => {
+                let (t, b) = orthonormal_basis(self.normal());
+                let d = sample_disk(u) * radius;
+                let point = center + t * d.x + b * d.y;
+
+                self.sample_point(from, point)
+            }
+            Light::Rect {
+                center,
+                u: edge_u,
+                v: edge_v,
+                ..
+            } => {
+                let (s, t) = (u.x * 2.0 - 1.0, u.y * 2.0 - 1.0);
+                let point = center + edge_u * s + edge_v * t;
+
+                self.sample_point(from, point)
+            }
+        }
+    }
+
+    /// Light sample towards a uniformly chosen `point` on a disk or rect
+    fn sample_point(&self, from: Point3f, point: Point3f) -> Option<LightSample> {
+        let to_light = point - from;
+        let distance = to_light.norm();
+        if distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+
+        let cos_light = direction.dot(&self.normal()).abs();
+        if cos_light <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.emitted(direction),
+            pdf: distance * distance / (self.area() * cos_light),
+            delta: false,
+            light: None,
+        })
+    }
+
+    /// Solid angle pdf of `sample` returning `direction` from `from`
+    fn pdf(&self, from: Point3f, direction: Vector3f) -> f32 {
+        match *self {
+            Light::Directional {
+                direction: light_direction,
+                cos_max,
+                ..
+            } if cos_max < 1.0 && -direction.dot(&light_direction) >= cos_max => {
+                1.0 / (2.0 * PI * (1.0 - cos_max))
+            }
+            Light::Disk { .. } | Light::Rect { .. } => {
+                let Some(distance) = self.intersect(from, direction) else {
+                    return 0.0;
+                };
+
+                let cos_light = direction.dot(&self.normal()).abs();
+                if cos_light <= 0.0 {
+                    return 0.0;
+                }
+
+                distance * distance / (self.area() * cos_light)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+pub struct LightSample {
+    /// unit vector from the shaded point towards the light
+    pub direction: Vector3f,
+    pub distance: f32,
+    pub radiance: Color,
+    /// with respect to solid angle, including the probability of picking the light
+    pub pdf: f32,
+    /// light only arrives from this one direction (point lights), so it can't be found by
+    /// sampling the bsdf
+    pub delta: bool,
+    /// the disk or rect light it's on, as its index in `Lights::analytic`. Shadow rays
+    /// towards it shouldn't count it as something in the way
+    pub light: Option<usize>,
+}
+
+/// All the lights in a scene, picked proportionally to their power when sampling
+#[derive(Default)]
+pub struct Lights {
+    pub area_lights: Vec<AreaLight>,
+    /// in the same space as the area lights
+    pub analytic: Vec<Light>,
+    /// over the area lights followed by the analytic ones
+    distribution: Distribution1D,
+    /// triangle index to index in `area_lights`, for lights that are hit by chance
+    by_triangle: HashMap<usize, usize>,
+
+    /// over the uv coordinates of the environment map, proportional to the light coming from
+    /// each texel
+    pub env: Distribution2D,
+    /// probability of sampling the environment instead of one of the other lights
+    pub env_probability: f32,
+}
+
+/// Combine two sampling strategies, returns the weight for the one with pdf `a`
+pub fn power_heuristic(a: f32, b: f32) -> f32 {
+    let a2 = a * a;
+    let b2 = b * b;
+
+    if a2 + b2 == 0.0 {
+        0.0
+    } else {
+        a2 / (a2 + b2)
+    }
+}
+
+impl Lights {
+    /// `analytic` lights have to be in the same space as the BVH already
+    pub fn new(bvh: &BvhScene, env_map: &DMatrix<Color>, analytic: Vec<Light>) -> Self {
+        let mut area_lights = Vec::new();
+
+        for (i, material) in bvh.materials.iter().enumerate() {
+            let Material::Emissive {
+                color,
+                strength,
+                two_sided,
+            } = material
+            else {
+                continue;
+            };
+
+            let tri = &bvh.triangles[i];
+            let cross = (tri.b - tri.a).cross(&(tri.c - tri.a));
+            let area = cross.norm() / 2.0;
+            if area <= 0.0 {
+                continue;
+            }
+
+            // the front face is the one the shading normals point out of
+            let (n0, n1, n2) = bvh.normals[i];
+            let mut normal = cross.normalize();
+            if normal.dot(&(n0 + n1 + n2)) < 0.0 {
+                normal = -normal;
+            }
+
+            area_lights.push(AreaLight {
+                triangle: i,
+                a: tri.a,
+                b: tri.b,
+                c: tri.c,
+                normal,
+                area,
+                emission: color * *strength,
+                two_sided: *two_sided,
+            });
+        }
+
+        // directional lights need to know how big the scene is to guess their power
+        let (min, max) = bvh.triangles.iter().flat_map(|t| [t.a, t.b, t.c]).fold(
+            (
+                Point3f::from(Vector3f::repeat(f32::INFINITY)),
+                Point3f::from(Vector3f::repeat(f32::NEG_INFINITY)),
+            ),
+            |(min, max), p| (min.inf(&p), max.sup(&p)),
+        );
+        let scene_radius = if bvh.triangles.is_empty() {
+            1.0
+        } else {
+            (max - min).norm() / 2.0
+        };
+
+        let distribution = Distribution1D::new(
+            area_lights
+                .iter()
+                .map(AreaLight::power)
+                .chain(analytic.iter().map(|light| light.power(scene_radius))),
+        );
+        let by_triangle = area_lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| (light.triangle, i))
+            .collect();
+
+        let env = env_distribution(env_map);
+        let env_probability = match (!env.is_empty(), distribution.is_empty()) {
+            (false, _) => 0.0,
+            (true, true) => 1.0,
+            (true, false) => 0.5,
+        };
+
+        Self {
+            area_lights,
+            analytic,
+            distribution,
+            by_triangle,
+            env,
+            env_probability,
+        }
+    }
+
+    /// Pick a point on one of the lights as seen from `from`, the pdf includes the probability
+    /// of not picking the environment. `u_pick` decides the light, `u` the point on it
+    pub fn sample(&self, from: Point3f, u_pick: f32, u: Vector2f) -> Option<LightSample> {
+        if self.distribution.is_empty() {
+            return None;
+        }
+
+        let (index, pick_pdf) = self.distribution.sample(u_pick);
+        let pick_pdf = pick_pdf * (1.0 - self.env_probability);
+
+        let mut sample = match index.checked_sub(self.area_lights.len()) {
+            None => self.area_lights[index].sample(from, u)?,
+            Some(index) => LightSample {
+                light: Some(index),
+                ..self.analytic[index].sample(from, u)?
+            },
+        };
+        sample.pdf *= pick_pdf;
+
+        Some(sample)
+    }
+
+    /// Solid angle pdf of `sample` picking `point` on the triangle `triangle` from `from`
+    pub fn pdf(&self, triangle: usize, from: Point3f, point: Point3f) -> f32 {
+        let Some(&index) = self.by_triangle.get(&triangle) else {
+            return 0.0;
+        };
+        if self.distribution.is_empty() {
+            return 0.0;
+        }
+        let light = &self.area_lights[index];
+
+        let to_light = point - from;
+        let distance2 = to_light.norm_squared();
+        let cos_light = to_light.normalize().dot(&light.normal).abs();
+        if cos_light <= 0.0 {
+            return 0.0;
+        }
+
+        let pick_pdf = self.distribution.pdf(index) * (1.0 - self.env_probability);
+        pick_pdf * distance2 / (light.area * cos_light)
+    }
+
+    /// Solid angle pdf of `sample` picking the analytic light `index` in `direction`
+    pub fn analytic_pdf(&self, index: usize, from: Point3f, direction: Vector3f) -> f32 {
+        if self.distribution.is_empty() {
+            return 0.0;
+        }
+
+        let pick_pdf = self.distribution.pdf(self.area_lights.len() + index);
+        let pick_pdf = pick_pdf * (1.0 - self.env_probability);
+
+        pick_pdf * self.analytic[index].pdf(from, direction)
+    }
+
+    /// Whether a disk or rect light, other than the one with index `skip`, is closer than
+    /// `distance` along a ray
+    pub fn occluded(
+        &self,
+        origin: Point3f,
+        direction: Vector3f,
+        distance: f32,
+        skip: Option<usize>,
+    ) -> bool {
+        self.analytic.iter().enumerate().any(|(i, light)| {
+            Some(i) != skip
+                && light
+                    .intersect(origin, direction)
+                    .is_some_and(|t| t < distance)
+        })
+    }
+
+    /// Closest disk or rect light along a ray, as its index in `analytic` and the distance
+    pub fn intersect(&self, origin: Point3f, direction: Vector3f) -> Option<(usize, f32)> {
+        self.analytic
+            .iter()
+            .enumerate()
+            .filter_map(|(i, light)| Some((i, light.intersect(origin, direction)?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Infinitely far away lights that can be seen along `direction`, with their index
+    pub fn distant(&self, direction: Vector3f) -> impl Iterator<Item = (usize, Color)> + '_ {
+        self.analytic
+            .iter()
+            .enumerate()
+            .filter(|(_, light)| matches!(light, Light::Directional { .. }))
+            .map(move |(i, light)| (i, light.emitted(direction)))
+            .filter(|(_, emitted)| *emitted != Color::zeros())
+    }
+}
+
+/// Distribution over the uv coordinates of an equirectangular environment map.
+/// Texels get weighted by the area they cover on the sphere, which shrinks with sin(theta)
+/// towards the poles
+fn env_distribution(env_map: &DMatrix<Color>) -> Distribution2D {
+    let (width, height) = env_map.shape();
+    let texel = |x: usize, y: usize| luminance(env_map[(x.min(width - 1), y.min(height - 1))]);
+
+    Distribution2D::new(width, height, |x, y| {
+        // the bilinear lookup blends in the next texel over, so a cell can't be left out if
+        // either of them is bright
+        let average = (texel(x, y) + texel(x + 1, y) + texel(x, y + 1) + texel(x + 1, y + 1)) / 4.0;
+        let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+
+        average * sin_theta
+    })
+}
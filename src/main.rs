@@ -1,153 +1,404 @@
-mod bsdf;
-mod camera;
-mod geom;
-mod objfile;
-mod render;
-mod rng;
-mod scene;
-mod texture;
-mod color;
-mod types;
-
-use exr::prelude::{write_rgb_file, ReadChannels, ReadLayers};
-use indicatif::{ProgressBar, ProgressIterator};
-use scene::Scene;
-use color::tonemap;
-use color::Color;
-use types::*;
-
-use std::{f64::consts::PI, time::Instant};
-
-use anyhow::Result;
-use camera::{perspective, Camera, UP};
-use geom::Transform;
-use nalgebra::{DMatrix, Vector3};
+use clap::Parser;
+use indicatif::ProgressBar;
+use raytracer::color::{DisplayTransform, Tonemap};
+use raytracer::denoise::Denoiser;
+use raytracer::filter::{Filter, FilterKind};
+use raytracer::output::{self, BitDepth};
+use raytracer::render::{new_film, Adaptive};
+use raytracer::tile::{Tile, TileOrder, Tiles};
+use raytracer::{checkpoint, render_from, sampler::SamplerKind, scenefile, Film};
 
-fn main() {
-    if let Err(e) = real_main() {
-        eprintln!("Error: {}", e);
-    }
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+#[derive(Parser)]
+#[command(about = "A CPU path tracer")]
+struct Args {
+    /// Scene description file
+    #[arg(default_value = "scene.toml")]
+    scene: PathBuf,
+
+    /// Where to write the rendered image: exr, hdr, pfm, png or ppm
+    #[arg(short, long, default_value = "output.exr")]
+    output: PathBuf,
+
+    /// Bits per channel for png and ppm output, 8 or 16
+    #[arg(long, default_value = "8")]
+    bit_depth: BitDepth,
+
+    /// Override the resolution from the scene file, e.g. 1280x720
+    #[arg(short, long, value_parser = parse_resolution)]
+    resolution: Option<(usize, usize)>,
+
+    /// Samples per pixel
+    #[arg(short, long)]
+    spp: Option<u32>,
+
+    /// Maximum number of bounces per path
+    #[arg(long)]
+    max_bounces: Option<u32>,
+
+    /// Number of render threads, defaults to one per core
+    #[arg(short, long)]
+    threads: Option<usize>,
+
+    /// Seed for the random number generator
+    #[arg(long)]
+    seed: Option<u32>,
+
+    /// How samples are distributed: independent, stratified, sobol or halton
+    #[arg(long)]
+    sampler: Option<SamplerKind>,
+
+    /// Pixel filter: box, tent, gaussian, mitchell or blackman-harris
+    #[arg(long)]
+    filter: Option<FilterKind>,
+
+    /// Pixel filter radius in pixels, defaults to one that suits the filter
+    #[arg(long)]
+    filter_radius: Option<f32>,
+
+    /// Also write depth, normals, IDs and lighting passes as layers of the EXR
+    #[arg(long)]
+    aovs: bool,
+
+    /// Also write Cryptomatte object and material mattes into the EXR
+    #[arg(long)]
+    cryptomatte: bool,
+
+    /// Denoise the finished image using the albedo, normal and depth of the first hits
+    #[arg(long)]
+    denoise: bool,
+
+    /// Stop sampling pixels once their noise drops below this, --spp becomes the most they get.
+    /// Writes a sample count layer into the EXR
+    #[arg(long)]
+    adaptive_threshold: Option<f32>,
+
+    /// Samples every pixel gets before adaptive sampling can stop it
+    #[arg(long)]
+    min_spp: Option<u32>,
+
+    /// Render in square tiles of this many pixels, each one finished before the next
+    #[arg(long)]
+    tile_size: Option<usize>,
+
+    /// Order to render tiles in: scanline, spiral or hilbert
+    #[arg(long)]
+    tile_order: Option<TileOrder>,
+
+    /// Only render part of the image, as x,y,width,height in pixels
+    #[arg(long, value_parser = parse_region)]
+    region: Option<Tile>,
+
+    /// Tonemapper for display formats: linear, reinhard, hable, aces or agx
+    #[arg(long)]
+    tonemap: Option<Tonemap>,
+
+    /// Display exposure adjustment in stops
+    #[arg(long, allow_negative_numbers = true)]
+    exposure: Option<f32>,
+
+    /// Stop after this long even if not all samples are taken, e.g. 90s, 10m or 1h.
+    /// Without --spp this keeps sampling until the time is up
+    #[arg(long, value_parser = parse_duration)]
+    time_budget: Option<Duration>,
+
+    /// Write the image so far to this file while rendering, tonemapped like the output
+    #[arg(long)]
+    preview: Option<PathBuf>,
+
+    /// How often to write the preview, e.g. 30s or 2m
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    preview_interval: Duration,
+
+    /// Also write the preview after every this many passes, or tiles when rendering in tiles
+    #[arg(long)]
+    preview_passes: Option<u32>,
+
+    /// Save the render progress to this file every --checkpoint-interval and at the end
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// How often to save a checkpoint, e.g. 90s, 10m or 1h
+    #[arg(long, value_parser = parse_duration, default_value = "5m")]
+    checkpoint_interval: Duration,
+
+    /// Carry on from the checkpoint file instead of starting over, as long as the scene and
+    /// settings are the same
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
 }
 
-fn rad(deg: f64) -> f64 {
-    deg * PI / 180.0
+fn parse_resolution(s: &str) -> Result<(usize, usize)> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| anyhow!("expected WIDTHxHEIGHT"))?;
+
+    let width = width.parse()?;
+    let height = height.parse()?;
+
+    if width == 0 || height == 0 {
+        return Err(anyhow!("resolution must be non-zero"));
+    }
+
+    Ok((width, height))
 }
 
-fn camera_transform(pos: Point3d) -> Transform {
-    let look_at = -pos.coords;
+fn parse_region(s: &str) -> Result<Tile> {
+    let values = s
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<Result<Vec<usize>, _>>()?;
 
-    Transform::new(
-        pos,
-        Quaternion::look_at_rh(&look_at, &UP),
-        Vector3::new(1.0, 1.0, 1.0),
-    )
+    let [x, y, width, height] = values[..] else {
+        return Err(anyhow!("expected x,y,width,height"));
+    };
+
+    Ok(Tile {
+        x,
+        y,
+        width,
+        height,
+    })
 }
 
-fn real_main() -> Result<()> {
-    let mut object1 = objfile::load_obj("sphere.obj", geom::Material::Glossy)?;
-    object1.transform = Transform::new(
-        Point3d::new(0.0, 1.0, 0.0),
-        Quaternion::from_euler_angles(0.0, 0.0, PI / 2.0),
-        Vector3::new(0.8, 0.8, 0.8),
-    );
-
-    let mut object2 = objfile::load_obj("smooth-monkey.obj", geom::Material::Diffuse(1.0))?;
-    object2.transform = Transform::new(
-        Point3d::new(0.0, -1.0, 0.0),
-        Quaternion::from_euler_angles(0.0, 0.0, PI / 2.0),
-        Vector3::new(0.8, 0.8, 0.8),
-    );
-
-    let hdri = exr::image::read::read()
-        .no_deep_data()
-        .largest_resolution_level()
-        .all_channels()
-        .all_layers()
-        .all_attributes()
-        .on_progress(|_| {})
-        .from_file("hdri.exr")?;
-
-    let hdri_layers = hdri.layer_data;
-    let hdri = &hdri_layers[0];
-
-    let hdri_width = hdri.size.x();
-    let hdri_height = hdri.size.y();
-
-    let hdri_channel = |name: &str| {
-        hdri.channel_data
-            .list
-            .iter()
-            .filter(|c| c.name == *name)
-            .next()
-            .unwrap()
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = match s.find(|c: char| c.is_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
     };
 
-    let hdri_r = hdri_channel("R").sample_data.values_as_f32();
-    let hdri_g = hdri_channel("G").sample_data.values_as_f32();
-    let hdri_b = hdri_channel("B").sample_data.values_as_f32();
+    let value: f64 = value.parse()?;
+    let seconds = match unit {
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 60.0 * 60.0,
+        _ => return Err(anyhow!("unknown unit '{}', expected s, m or h", unit)),
+    };
 
-    let rgb = hdri_r
-        .zip(hdri_g)
-        .zip(hdri_b)
-        .map(|((r, g), b)| Color::new(r, g, b));
+    Ok(Duration::try_from_secs_f64(seconds)?)
+}
 
-    let hdri_rgb = DMatrix::from_iterator(hdri_width, hdri_height, rgb);
+/// Write to a temporary file first, so image viewers never see a half written preview
+fn save_preview(
+    film: &Film,
+    path: &Path,
+    display: &DisplayTransform,
+    depth: BitDepth,
+) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("preview path has no file name"))?;
+    let tmp_path = path.with_file_name(format!(".tmp-{}", file_name.to_string_lossy()));
 
-    // let hdri_width = 2048;
-    // let hdri_height = 1024;
-    // let hdri_gray = DMatrix::zeros(2048, 1024);
+    output::save(film, &tmp_path, display, depth)?;
+    fs::rename(&tmp_path, path)?;
 
-    println!("Loaded HDRI with resolution {}x{}", hdri_width, hdri_height);
+    Ok(())
+}
 
-    let viewport_width = 1920;
-    let viewport_height = 1080;
-    let aspect = viewport_width as f32 / viewport_height as f32;
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+}
 
-    let fov = rad(50.0);
+fn real_main() -> Result<()> {
+    let args = Args::parse();
 
-    let camera = Camera::new(
-        viewport_width,
-        viewport_height,
-        camera_transform(Point3d::new(2.5, 0.5, 1.0)),
-        perspective(fov as f32, aspect),
-    );
+    let (mut scene, mut settings) = scenefile::load_scene(&args.scene)?;
+    // a plain color is a single pixel
+    if scene.env_map.len() > 1 {
+        println!(
+            "Loaded HDRI with resolution {}x{}",
+            scene.env_map.nrows(),
+            scene.env_map.ncols()
+        );
+    }
 
-    let mut scene = Scene::new(camera, vec![object1, object2], hdri_rgb);
+    if let Some((width, height)) = args.resolution {
+        scene.camera.resize(width, height);
+    }
+    if let Some(spp) = args.spp {
+        settings.samples = spp;
+    }
+    if let Some(max_bounces) = args.max_bounces {
+        settings.max_bounces = max_bounces;
+    }
+    if let Some(seed) = args.seed {
+        settings.seed = seed;
+    }
+    if let Some(sampler) = args.sampler {
+        settings.sampler = sampler;
+    }
+    if args.filter.is_some() || args.filter_radius.is_some() {
+        let kind = args.filter.unwrap_or(settings.filter.kind);
+        let radius = match args.filter_radius {
+            Some(radius) if radius <= 0.0 => {
+                return Err(anyhow!("filter radius has to be positive"))
+            }
+            Some(radius) => radius,
+            // a different filter gets its own default radius
+            None if args.filter.is_some() => kind.default_radius(),
+            None => settings.filter.radius,
+        };
+        settings.filter = Filter::new(kind, radius);
+    }
+    if let Some(tonemap) = args.tonemap {
+        settings.display.tonemap = tonemap;
+    }
+    if let Some(exposure) = args.exposure {
+        settings.display.exposure = exposure;
+    }
+    if args.tile_size.is_some() || args.tile_order.is_some() {
+        let tiles = settings.tiles.get_or_insert_with(Tiles::default);
+        if let Some(size) = args.tile_size {
+            if size == 0 {
+                return Err(anyhow!("tile size must be non-zero"));
+            }
+            tiles.size = size;
+        }
+        if let Some(order) = args.tile_order {
+            tiles.order = order;
+        }
+    }
+    if args.region.is_some() {
+        settings.region = args.region;
+    }
+    if let Some(region) = settings.region {
+        if !region.fits(scene.camera.width, scene.camera.height) {
+            return Err(anyhow!(
+                "the region has to be a non-empty part of the image"
+            ));
+        }
+    }
+    settings.time_budget = args.time_budget;
+    if args.time_budget.is_some() && args.spp.is_none() {
+        // the strata are laid out for a known number of samples
+        if settings.sampler == SamplerKind::Stratified {
+            return Err(anyhow!(
+                "the stratified sampler needs --spp to go with --time-budget"
+            ));
+        }
+        if settings.tiles.is_some() {
+            return Err(anyhow!("tiles need --spp to go with --time-budget"));
+        }
+        settings.samples = u32::MAX;
+    }
+    settings.aovs |= args.aovs;
+    settings.cryptomatte |= args.cryptomatte;
+    if args.denoise && settings.denoise.is_none() {
+        settings.denoise = Some(Denoiser::default());
+    }
+    if let Some(threshold) = args.adaptive_threshold {
+        if threshold <= 0.0 {
+            return Err(anyhow!("adaptive threshold has to be positive"));
+        }
+        let adaptive = settings.adaptive.get_or_insert_with(Adaptive::default);
+        adaptive.threshold = threshold;
+    }
+    if let Some(min_spp) = args.min_spp {
+        let Some(adaptive) = &mut settings.adaptive else {
+            return Err(anyhow!(
+                "--min-spp needs adaptive sampling, from --adaptive-threshold or the scene file"
+            ));
+        };
+        adaptive.min_samples = min_spp;
+    }
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
 
     println!("Starting render");
 
     scene.build_bvh();
-    let samples = 1024;
-    let bar = ProgressBar::new(samples as u64);
 
-    let mut fb: DMatrix<_> = DMatrix::zeros(viewport_width, viewport_height);
-    let mut sample_times = Vec::with_capacity(samples as usize);
+    let hash = checkpoint::render_hash(&scene, &settings);
+    let mut film = new_film(&scene, &settings);
+    if args.resume {
+        let path = args.checkpoint.as_ref().unwrap();
+        checkpoint::load(&mut film, path, hash)?;
+        println!("Resuming from {} samples", film.samples);
+    }
+
+    // without a sample count the time budget is what the render runs up to
+    let until_time_is_up = settings.samples == u32::MAX;
+    let bar = match (settings.time_budget, &settings.tiles) {
+        (Some(budget), _) if until_time_is_up => ProgressBar::new(budget.as_secs()),
+        // one step per tile, resumed tiles count as well
+        (_, Some(tiles)) => {
+            let region = settings
+                .region
+                .unwrap_or(Tile::full(scene.camera.width, scene.camera.height));
+            ProgressBar::new(tiles.split(region).len() as u64)
+        }
+        _ => {
+            let bar = ProgressBar::new(settings.samples as u64);
+            bar.set_position(film.samples as u64);
+            bar
+        }
+    };
+    let mut sample_times = Vec::new();
+    let render_start = Instant::now();
+    let mut time_start = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    let mut last_preview = Instant::now();
+
+    let film = render_from(&scene, &settings, film, |film| {
+        sample_times.push(time_start.elapsed());
+        time_start = Instant::now();
+        if until_time_is_up {
+            bar.set_position(render_start.elapsed().as_secs());
+        } else {
+            bar.inc(1);
+        }
+
+        if let Some(path) = &args.preview {
+            let passes = sample_times.len() as u32;
+            let every_passes = args
+                .preview_passes
+                .is_some_and(|every| passes.is_multiple_of(every));
+            if every_passes || last_preview.elapsed() >= args.preview_interval {
+                if let Err(e) = save_preview(film, path, &settings.display, args.bit_depth) {
+                    bar.println(format!("Failed to write preview: {:#}", e));
+                }
+                last_preview = Instant::now();
+            }
+        }
 
-    for _ in (0..samples).progress_with(bar) {
-        let time_start = Instant::now();
-        fb += render::sample_once(&scene);
+        if let Some(path) = &args.checkpoint {
+            if last_checkpoint.elapsed() >= args.checkpoint_interval {
+                // losing a checkpoint isn't worth stopping the render for
+                if let Err(e) = checkpoint::save(film, path, hash) {
+                    bar.println(format!("Failed to save checkpoint: {:#}", e));
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+    });
+    bar.finish();
 
-        let elapsed = time_start.elapsed();
-        sample_times.push(elapsed);
+    if let Some(path) = &args.checkpoint {
+        checkpoint::save(&film, path, hash)?;
     }
 
     // use last 8 samples to estimate time
-    let time_per_sample = sample_times.iter().rev().take(8).sum::<std::time::Duration>() / 8;
+    let last = sample_times.len().clamp(1, 8) as u32;
+    let time_per_sample = sample_times.iter().rev().take(8).sum::<Duration>() / last;
     println!("Time per sample: {:?}", time_per_sample);
+    println!("Took {} samples in {:?}", film.samples, render_start.elapsed());
 
-    write_rgb_file(
-        // "output.exr",
-        &format!("output.exr"),
-        viewport_width as usize,
-        viewport_height as usize,
-        |x, y| {
-            let rgb = fb[(x, y)];
-            let rgb = rgb / samples as f32;
-
-            let rgb = tonemap(rgb);
-            (rgb.x, rgb.y, rgb.z)
-        },
-    )?;
+    output::save(&film, &args.output, &settings.display, args.bit_depth)?;
 
     Ok(())
 }
@@ -1,6 +1,7 @@
 use std::{
     fs::File,
     io::{BufRead, BufReader, Write},
+    path::Path,
 };
 
 use crate::geom::{Material, Mesh, Object, Transform};
@@ -63,7 +64,14 @@ pub fn load_obj(path: &str, material: Material) -> anyhow::Result<Object> {
         }
     }
 
+    // name objects after their file until told otherwise
+    let name = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
     Ok(Object {
+        name,
         transform: Transform::identity(),
         mesh: Mesh {
             vertices,
@@ -72,6 +80,7 @@ pub fn load_obj(path: &str, material: Material) -> anyhow::Result<Object> {
             normal_triangles,
         },
         material,
+        material_name: String::new(),
     })
 }
 
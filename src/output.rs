@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use exr::meta::attribute::{AttributeValue, Text};
+use exr::prelude::{
+    write_rgb_file, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes,
+    SmallVec, WritableImage,
+};
+
+use crate::aov::Aovs;
+use crate::color::DisplayTransform;
+use crate::cryptomatte::RANKS;
+use crate::film::Film;
+use crate::rng::hash;
+use crate::Color;
+
+/// Bits per channel for the integer formats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+}
+
+impl FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(BitDepth::Eight),
+            "16" => Ok(BitDepth::Sixteen),
+            _ => Err(format!("unsupported bit depth '{}', expected 8 or 16", s)),
+        }
+    }
+}
+
+impl BitDepth {
+    fn max_value(self) -> f32 {
+        match self {
+            BitDepth::Eight => 255.0,
+            BitDepth::Sixteen => 65535.0,
+        }
+    }
+}
+
+/// Write the film to an image file, the format is picked from the file extension.
+/// EXR, HDR and PFM keep the linear values, PNG and PPM go through `display` and get `depth`
+/// bits per channel
+pub fn save(
+    film: &Film,
+    path: impl AsRef<Path>,
+    display: &DisplayTransform,
+    depth: BitDepth,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "exr" => save_exr(film, path),
+        "hdr" => save_hdr(film, path),
+        "pfm" => save_pfm(film, path),
+        "png" => save_png(film, path, display, depth),
+        "ppm" => save_ppm(film, path, display, depth),
+        _ => bail!(
+            "unsupported output format '{}', expected exr, hdr, pfm, png or ppm",
+            path.display()
+        ),
+    }
+}
+
+fn save_exr(film: &Film, path: &Path) -> anyhow::Result<()> {
+    if film.has_aovs() || film.cryptomatte().is_some() || film.has_sample_counts() {
+        return save_layered_exr(film, path);
+    }
+
+    write_rgb_file(path, film.width, film.height, |x, y| {
+        // linear, exposed but not tonemapped
+        let rgb = film.pixel(x, y);
+        (rgb.x, rgb.y, rgb.z)
+    })?;
+
+    Ok(())
+}
+
+/// Beauty in R, G and B with every AOV as `layer.channel` next to it, the way Nuke and most
+/// compositors expect layers in a single part EXR. Cryptomatte mattes go in as well, with their
+/// metadata in the header, and so do the sample counts of adaptive sampling
+fn save_layered_exr(film: &Film, path: &Path) -> anyhow::Result<()> {
+    let mut pixels = Vec::with_capacity(film.width * film.height);
+    for y in 0..film.height {
+        for x in 0..film.width {
+            pixels.push((film.pixel(x, y), film.aovs(x, y).unwrap_or_default()));
+        }
+    }
+
+    let channel = |name: &str, value: &dyn Fn(&(Color, Aovs)) -> f32| {
+        AnyChannel::new(name, FlatSamples::F32(pixels.iter().map(value).collect()))
+    };
+
+    let mut channels = vec![
+        channel("R", &|(rgb, _)| rgb.x),
+        channel("G", &|(rgb, _)| rgb.y),
+        channel("B", &|(rgb, _)| rgb.z),
+    ];
+
+    if film.has_aovs() {
+        channels.push(channel("depth.Z", &|(_, aov)| aov.depth));
+        channels.push(channel("object_id.id", &|(_, aov)| aov.object_id as f32));
+        channels.push(channel("material_id.id", &|(_, aov)| {
+            aov.material_id as f32
+        }));
+
+        // geometry gets X, Y and Z, colors R, G and B
+        let mut add_layer = |layer: &str, names: [&str; 3], value: fn(&Aovs) -> Color| {
+            for (i, name) in names.iter().enumerate() {
+                let name = format!("{}.{}", layer, name);
+                channels.push(channel(&name, &|(_, aov)| value(aov)[i]));
+            }
+        };
+        let xyz = ["X", "Y", "Z"];
+        let rgb = ["R", "G", "B"];
+
+        add_layer("position", xyz, |aov| aov.position);
+        add_layer("camera_position", xyz, |aov| aov.camera_position);
+        add_layer("normal", xyz, |aov| aov.normal);
+        add_layer("albedo", rgb, |aov| aov.albedo);
+        add_layer("emission", rgb, |aov| aov.emission);
+        add_layer("direct", rgb, |aov| aov.direct);
+        add_layer("indirect", rgb, |aov| aov.indirect);
+        add_layer("diffuse", rgb, |aov| aov.diffuse);
+        add_layer("specular", rgb, |aov| aov.specular);
+    }
+
+    if film.has_sample_counts() {
+        let counts = (0..film.width * film.height)
+            .map(|i| film.sample_count(i) as f32)
+            .collect();
+        channels.push(AnyChannel::new("sample_count.Y", FlatSamples::F32(counts)));
+    }
+
+    let mut attributes = LayerAttributes::default();
+
+    if let Some(cryptomatte) = film.cryptomatte() {
+        for matte in [&cryptomatte.objects, &cryptomatte.materials] {
+            let mut ranked = Vec::with_capacity(film.width * film.height);
+            for y in 0..film.height {
+                for x in 0..film.width {
+                    ranked.push(matte.ranked(y * film.width + x, film.weight(x, y)));
+                }
+            }
+
+            // two ranks per channel set, the ID in R and B and its coverage in G and A
+            for set in 0..RANKS / 2 {
+                for (i, name) in ["R", "G", "B", "A"].iter().enumerate() {
+                    let rank = set * 2 + i / 2;
+                    let values = ranked
+                        .iter()
+                        .map(|ranks| {
+                            let (id, coverage) = ranks[rank];
+                            if i % 2 == 0 {
+                                id
+                            } else {
+                                coverage
+                            }
+                        })
+                        .collect();
+
+                    let name = format!("{}{:02}.{}", matte.layer, set, name);
+                    channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(values)));
+                }
+            }
+
+            for (key, value) in matte.metadata() {
+                // the spec wants UTF-8, which exr's text type doesn't check for
+                let text =
+                    |s: String| Text::from_bytes_unchecked(SmallVec::from_vec(s.into_bytes()));
+                attributes
+                    .other
+                    .insert(text(key), AttributeValue::Text(text(value)));
+            }
+        }
+    }
+
+    let layer = Layer::new(
+        (film.width, film.height),
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(SmallVec::from_vec(channels)),
+    );
+
+    Image::from_layer(layer).write().to_file(path)?;
+
+    Ok(())
+}
+
+/// Display values for the whole image in rows from the top, quantized to `depth` with a bit of
+/// triangular noise so smooth gradients don't band
+fn quantize(film: &Film, display: &DisplayTransform, depth: BitDepth) -> Vec<u16> {
+    let max = depth.max_value();
+    let mut out = Vec::with_capacity(film.width * film.height * 3);
+
+    for y in 0..film.height {
+        for x in 0..film.width {
+            let rgb = display.apply(film.pixel(x, y));
+
+            for (channel, value) in rgb.iter().enumerate() {
+                let noise = hash((y * film.width + x) as u64, channel as u64);
+                let u1 = (noise & 0xffff_ffff) as f32 / u32::MAX as f32;
+                let u2 = (noise >> 32) as f32 / u32::MAX as f32;
+                let dither = u1 - u2;
+
+                out.push((value * max + 0.5 + dither).clamp(0.0, max) as u16);
+            }
+        }
+    }
+
+    out
+}
+
+fn save_png(
+    film: &Film,
+    path: &Path,
+    display: &DisplayTransform,
+    depth: BitDepth,
+) -> anyhow::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+
+    let mut encoder = png::Encoder::new(file, film.width as u32, film.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(match depth {
+        BitDepth::Eight => png::BitDepth::Eight,
+        BitDepth::Sixteen => png::BitDepth::Sixteen,
+    });
+
+    let values = quantize(film, display, depth);
+    let data: Vec<u8> = match depth {
+        BitDepth::Eight => values.iter().map(|&v| v as u8).collect(),
+        BitDepth::Sixteen => values.iter().flat_map(|v| v.to_be_bytes()).collect(),
+    };
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Binary PPM (P6)
+fn save_ppm(
+    film: &Film,
+    path: &Path,
+    display: &DisplayTransform,
+    depth: BitDepth,
+) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(
+        file,
+        "P6\n{} {}\n{}\n",
+        film.width,
+        film.height,
+        depth.max_value() as u32
+    )?;
+
+    for value in quantize(film, display, depth) {
+        match depth {
+            BitDepth::Eight => file.write_all(&[value as u8])?,
+            BitDepth::Sixteen => file.write_all(&value.to_be_bytes())?,
+        }
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Shared exponent encoding of Radiance HDR files
+fn rgbe(color: Color) -> [u8; 4] {
+    let max = color.max();
+    if max < 1e-32 {
+        return [0; 4];
+    }
+
+    // max = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2.0f32.powi(exponent);
+    let channel = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+
+    [
+        channel(color.x),
+        channel(color.y),
+        channel(color.z),
+        (exponent + 128).clamp(0, 255) as u8,
+    ]
+}
+
+/// Radiance RGBE, uncompressed scanlines
+fn save_hdr(film: &Film, path: &Path) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(
+        file,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        film.height, film.width
+    )?;
+
+    for y in 0..film.height {
+        for x in 0..film.width {
+            file.write_all(&rgbe(film.pixel(x, y)))?;
+        }
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Portable float map, little endian and stored from the bottom row up
+fn save_pfm(film: &Film, path: &Path) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", film.width, film.height)?;
+
+    for y in (0..film.height).rev() {
+        for x in 0..film.width {
+            for value in film.pixel(x, y).iter() {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()?;
+
+    Ok(())
+}
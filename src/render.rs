@@ -1,58 +1,243 @@
-use nalgebra::{DMatrix, Point3};
-
-use crate::geom::normalize;
-use crate::rng::{rand_circle, rand_f32};
+use std::time::{Duration, Instant};
+
+use crate::aov::Aovs;
+use crate::color::DisplayTransform;
+use crate::cryptomatte::Cryptomatte;
+use crate::denoise::Denoiser;
+use crate::film::{Film, PixelSample};
+use crate::filter::Filter;
+use crate::sampler::SamplerKind;
 use crate::scene::Scene;
+use crate::tile::{Tile, Tiles};
 
-use crate::{Color, Point3f, Ray, Vector3f};
+use crate::{Color, Vector2f};
 
 use rayon::prelude::*;
 
-pub fn sample_once(scene: &Scene) -> DMatrix<Color> {
+/// Stop sampling pixels once they look converged
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    /// standard error of a pixel's display brightness (0..1) below which it counts as converged
+    pub threshold: f32,
+    /// samples every pixel gets before it can stop, `RenderSettings::samples` is the most
+    pub min_samples: u32,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            threshold: 0.01,
+            min_samples: 16,
+        }
+    }
+}
+
+pub struct RenderSettings {
+    pub samples: u32,
+    pub max_bounces: u32,
+    pub seed: u32,
+    pub sampler: SamplerKind,
+    /// how samples get spread over and averaged into pixels
+    pub filter: Filter,
+    /// stop taking samples once this much time has passed, even if `samples` isn't reached
+    pub time_budget: Option<Duration>,
+    /// how the image is turned into display values for formats that need them
+    pub display: DisplayTransform,
+    /// record AOVs (depth, normals, IDs, lighting passes...) alongside the image
+    pub aovs: bool,
+    /// record Cryptomatte ID mattes for objects and materials
+    pub cryptomatte: bool,
+    /// denoise the finished image, this records the AOVs it needs as well
+    pub denoise: Option<Denoiser>,
+    /// skip pixels that already converged, also writes the sample counts as an AOV
+    pub adaptive: Option<Adaptive>,
+    /// render tile by tile, every tile gets all its samples before the next one starts
+    pub tiles: Option<Tiles>,
+    /// only render this part of the image, the rest stays black
+    pub region: Option<Tile>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            samples: 1024,
+            max_bounces: 16,
+            seed: 0,
+            sampler: SamplerKind::default(),
+            filter: Filter::default(),
+            time_budget: None,
+            display: DisplayTransform::default(),
+            aovs: false,
+            cryptomatte: false,
+            denoise: None,
+            adaptive: None,
+            tiles: None,
+            region: None,
+        }
+    }
+}
+
+/// Render the scene, `on_pass` is called after every pass over the image.
+/// The scene's BVH has to be built before calling this.
+pub fn render(scene: &Scene, settings: &RenderSettings, on_pass: impl FnMut(&Film)) -> Film {
+    render_from(scene, settings, new_film(scene, settings), on_pass)
+}
+
+/// An empty film that records what `settings` ask for
+pub fn new_film(scene: &Scene, settings: &RenderSettings) -> Film {
     let camera = &scene.camera;
-    let n_pixels = camera.width * camera.height;
+    let mut film = if settings.aovs || settings.denoise.is_some() {
+        Film::with_aovs(camera.width, camera.height)
+    } else {
+        Film::new(camera.width, camera.height)
+    };
+    if settings.cryptomatte {
+        let object_names = scene.objects.iter().map(|object| object.name.clone()).collect();
+        film = film.with_cryptomatte(Cryptomatte::new(
+            object_names,
+            scene.material_names(),
+            camera.width * camera.height,
+        ));
+    }
+    if settings.adaptive.is_some() {
+        film = film.with_sample_counts();
+    }
+
+    film
+}
 
-    let viewport_width = camera.width as f32;
-    let viewport_height = camera.height as f32;
+/// Like `render`, but carry on with samples already in `film`, like ones from a checkpoint.
+/// When rendering in tiles `on_pass` is called after every finished tile instead
+pub fn render_from(
+    scene: &Scene,
+    settings: &RenderSettings,
+    mut film: Film,
+    mut on_pass: impl FnMut(&Film),
+) -> Film {
+    assert!(scene.bvh.is_some(), "Scene::build_bvh has to be called before rendering");
 
-    #[rustfmt::skip]
-    let fb: Vec<_> = (0..n_pixels).into_par_iter().map(|i| {
-    // let fb: Vec<_> = (0..n_pixels).map(|i| {
-        let x = i % viewport_width as usize;
-        let y = i / viewport_width as usize;
+    let camera = &scene.camera;
+    let region = settings.region.unwrap_or(Tile::full(camera.width, camera.height));
+    let deadline = settings.time_budget.map(|budget| Instant::now() + budget);
+
+    match &settings.tiles {
+        Some(tiles) => {
+            for tile in tiles.split(region) {
+                let mut tile_film = film.crop(&tile);
+                let pixels: Vec<usize> = (0..tile.width * tile.height).collect();
+
+                let to_image = |i| tile.pixel(i, camera.width);
+                while take_pass(scene, settings, &mut tile_film, &pixels, to_image, deadline) {}
+
+                film.paste(&tile_film, &tile);
+                on_pass(&film);
+            }
+        }
+        None => {
+            let pixels = region.pixels(camera.width);
+            while take_pass(scene, settings, &mut film, &pixels, |i| i, deadline) {
+                on_pass(&film);
+            }
+        }
+    }
+
+    if let Some(denoiser) = &settings.denoise {
+        film.denoise(denoiser);
+    }
+
+    film
+}
+
+/// Add another sample to the `pixels` of `film` that still need one, `to_image` turns them
+/// into pixels of the whole image. False once there's nothing left to do
+fn take_pass(
+    scene: &Scene,
+    settings: &RenderSettings,
+    film: &mut Film,
+    pixels: &[usize],
+    to_image: impl Fn(usize) -> usize,
+    deadline: Option<Instant>,
+) -> bool {
+    if deadline.is_some_and(|end| Instant::now() >= end) {
+        return false;
+    }
+
+    let pixels: Vec<usize> = pixels
+        .iter()
+        .copied()
+        .filter(|&i| {
+            let count = film.sample_count(i);
+            let converged = settings.adaptive.is_some_and(|adaptive| {
+                count >= adaptive.min_samples && film.error(i) <= adaptive.threshold
+            });
+            count < settings.samples && !converged
+        })
+        .collect();
+    if pixels.is_empty() {
+        return false;
+    }
+
+    let image_pixels: Vec<usize> = pixels.iter().map(|&i| to_image(i)).collect();
+    let samples: Vec<u32> = pixels.iter().map(|&i| film.sample_count(i)).collect();
+    film.add_samples(&pixels, &sample_pixels(scene, settings, &image_pixels, &samples));
+
+    true
+}
+
+/// Take sample number `sample` of every pixel, the same sample of the same scene and settings
+/// always comes out the same
+pub fn sample_once(scene: &Scene, settings: &RenderSettings, sample: u32) -> Vec<PixelSample> {
+    let camera = &scene.camera;
+    let pixels: Vec<usize> = (0..camera.width * camera.height).collect();
 
-        let x = x as f32;
-        let y = y as f32;
+    sample_pixels(scene, settings, &pixels, &vec![sample; pixels.len()])
+}
 
-        let jitter = rand_circle();
-        let x = x + jitter.x;
-        let y = y + jitter.y;
+/// Take sample number `samples[i]` of pixel `pixels[i]`, with pixels numbered x first
+pub fn sample_pixels(
+    scene: &Scene,
+    settings: &RenderSettings,
+    pixels: &[usize],
+    samples: &[u32],
+) -> Vec<PixelSample> {
+    let camera = &scene.camera;
 
-        let ndc_x = (2.0 * x) / viewport_width - 1.0;
-        let ndc_y = 1.0 - (2.0 * y) / viewport_height;
-        let ndc_z = 1.0;
+    let viewport_width = camera.width as f32;
+    let response = camera.sensor_response();
+    let want_aovs = settings.aovs || settings.cryptomatte || settings.denoise.is_some();
 
-        let ndc_point = Point3f::new(ndc_x, ndc_y, ndc_z);
+    #[rustfmt::skip]
+    let fb: Vec<_> = pixels.par_iter().zip(samples).map(|(&i, &sample)| {
+    // let fb: Vec<_> = pixels.iter().zip(samples).map(|(&i, &sample)| {
+        let mut sampler = settings.sampler.for_pixel(i, sample, settings.samples, settings.seed);
 
-        let camera_space_point = camera.inv_projection.transform_point(&ndc_point);
-        let ray_dir = camera_space_point.coords;
+        let x = i % viewport_width as usize;
+        let y = i / viewport_width as usize;
 
-        let wow = camera.transform.inv_matrix_f;
-        let ray_dir = wow.transform_vector(&ray_dir);
-        let ray_dir = wow.transform_vector(&ray_dir);
+        let (offset, weight) = settings.filter.sample(sampler.get_2d());
+        let x = x as f32 + 0.5 + offset.x;
+        let y = y as f32 + 0.5 + offset.y;
 
-        let ray_dir = normalize(ray_dir);
+        let ray = camera.generate_ray(Vector2f::new(x, y), sampler.get_2d());
 
-        let ray_dir_inv = Vector3f::new(1.0 / ray_dir.x, 1.0 / ray_dir.y, 1.0 / ray_dir.z);
-        let ray = Ray {
-            origin: Point3::new(0.0, 0.0, 0.0),
-            direction: ray_dir,
-            inv_direction: ray_dir_inv,
+        let (radiance, aovs) = match ray {
+            Some(ray) if want_aovs => {
+                let (radiance, aovs) =
+                    scene.sample_aovs(&ray, settings.max_bounces, sampler.as_mut());
+                (radiance, Some(aovs))
+            }
+            Some(ray) => (scene.sample(&ray, settings.max_bounces, sampler.as_mut()), None),
+            None => (Color::zeros(), want_aovs.then(Aovs::default)),
         };
 
-        let b = scene.sample(&ray, 16);
-        b
+        let radiance = response * radiance;
+        let aovs = aovs.map(|mut aovs| {
+            aovs.expose(&response);
+            Box::new(aovs)
+        });
+
+        PixelSample { radiance, weight, aovs }
     }).collect();
 
-    DMatrix::from_vec(camera.width, camera.height, fb)
+    fb
 }
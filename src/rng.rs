@@ -1,54 +1,62 @@
-use std::f32::consts::PI;
-
-use crate::{geom::normalize, Vector2f, Vector3f};
+/// PCG32 random number generator, "PCG: A Family of Simple Fast Space-Efficient Statistically
+/// Good Algorithms for Random Number Generation" (O'Neill 2014).
+/// Every pixel sample gets its own, so renders don't depend on how threads get scheduled
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+    inc: u64,
+}
 
-static mut SEED: u32 = 0;
+const MULTIPLIER: u64 = 6364136223846793005;
 
-pub fn rand() -> u32 {
-    unsafe {
-        SEED = SEED.wrapping_mul(1664525).wrapping_add(1013904223);
-        SEED
-    }
+/// Scramble the bits of `x` so that nearby inputs give unrelated outputs (splitmix64)
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
 }
 
-pub fn rand_f32() -> f32 {
-    let x: u32 = rand();
-    x as f32 / u32::MAX as f32
+/// Combine two values into one well mixed hash
+pub fn hash(a: u64, b: u64) -> u64 {
+    mix(a ^ mix(b))
 }
 
-pub fn rand_circle() -> Vector2f {
-    let theta = 2.0 * PI * rand_f32();
-    let rho = rand_f32().sqrt();
+impl Rng {
+    /// Generators with different `stream`s give different sequences even for the same `seed`
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
 
-    Vector2f::new(rho * theta.cos(), rho * theta.sin())
-}
+        rng.rand();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.rand();
 
-pub fn rand_norm_f32() -> f32 {
-    let theta = 2.0 * PI * rand_f32();
-    let rho = (-2.0 * rand_f32().ln()).sqrt();
+        rng
+    }
 
-    rho * theta.cos()
-}
+    /// Generator for one sample of one pixel
+    pub fn for_pixel(pixel: usize, sample: u32, seed: u32) -> Self {
+        let pixel_seed = mix(pixel as u64 ^ ((seed as u64) << 32));
+        let sample_stream = mix(sample as u64 ^ ((seed as u64) << 32));
 
-pub fn rand_direction() -> Vector3f {
-    let x = rand_norm_f32();
-    let y = rand_norm_f32();
-    let z = rand_norm_f32();
+        Self::new(pixel_seed, sample_stream)
+    }
 
-    // Vector3f::new(x, y, z).normalize()
-    normalize(Vector3f::new(x, y, z))
-}
+    pub fn rand(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(self.inc);
 
-pub fn rand_hemisphere(normal: Vector3f) -> Vector3f {
-    let mut dir = rand_direction();
-    while dir.norm() > 1.0 {
-        dir = rand_direction();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
     }
 
-    if dir.dot(&normal) < 0.0 {
-        -dir
-    } else {
-        dir
+    /// Uniform in [0, 1)
+    pub fn rand_f32(&mut self) -> f32 {
+        // the top 24 bits fit into the mantissa exactly
+        (self.rand() >> 8) as f32 / (1u32 << 24) as f32
     }
 }
 
@@ -0,0 +1,380 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::rng::{hash, Rng};
+use crate::{Vector2f, Vector3f};
+
+/// Hands out the random numbers for one sample of one pixel. Every call moves on to the next
+/// dimension, so the integrator has to ask for them in the same order for every sample
+pub trait Sampler {
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> Vector2f;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// plain pseudo-random numbers
+    Independent,
+    /// jittered strata, shuffled separately for every dimension
+    Stratified,
+    /// Owen-scrambled Sobol points
+    #[default]
+    Sobol,
+    /// Halton points, randomly shifted for every pixel
+    Halton,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "halton" => Ok(SamplerKind::Halton),
+            _ => Err(format!(
+                "unknown sampler '{}', expected independent, stratified, sobol or halton",
+                s
+            )),
+        }
+    }
+}
+
+impl SamplerKind {
+    /// Sampler for sample number `sample` (out of `samples`) of pixel `pixel`
+    pub fn for_pixel(self, pixel: usize, sample: u32, samples: u32, seed: u32) -> Box<dyn Sampler> {
+        let pixel_seed = hash(pixel as u64, seed as u64);
+
+        match self {
+            SamplerKind::Independent => Box::new(Independent {
+                rng: Rng::for_pixel(pixel, sample, seed),
+            }),
+            SamplerKind::Stratified => Box::new(Stratified {
+                rng: Rng::for_pixel(pixel, sample, seed),
+                pixel_seed,
+                sample,
+                samples: samples.max(1),
+                dimension: 0,
+            }),
+            SamplerKind::Sobol => Box::new(Sobol {
+                pixel_seed,
+                sample,
+                dimension: 0,
+            }),
+            SamplerKind::Halton => Box::new(Halton {
+                rng: Rng::for_pixel(pixel, sample, seed),
+                pixel_seed,
+                sample,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+/// Map 32 random bits to [0, 1)
+fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+pub struct Independent {
+    rng: Rng,
+}
+
+impl Sampler for Independent {
+    fn get_1d(&mut self) -> f32 {
+        self.rng.rand_f32()
+    }
+
+    fn get_2d(&mut self) -> Vector2f {
+        Vector2f::new(self.rng.rand_f32(), self.rng.rand_f32())
+    }
+}
+
+/// Element `i` of a random permutation of `0..len` picked by `seed`, without building it.
+/// "Correlated Multi-Jittered Sampling" (Kensler 2013)
+fn permutation_element(mut i: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    // cycle walk until the hashed value lands inside the range
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < len {
+            break;
+        }
+    }
+
+    i.wrapping_add(seed) % len
+}
+
+/// Splits every dimension into `samples` strata (or a square grid of them for pairs) and
+/// gives every sample its own stratum, in a different random order per dimension
+pub struct Stratified {
+    rng: Rng,
+    pixel_seed: u64,
+    sample: u32,
+    samples: u32,
+    dimension: u32,
+}
+
+impl Stratified {
+    fn next_seed(&mut self) -> u32 {
+        self.dimension += 1;
+        hash(self.pixel_seed, self.dimension as u64) as u32
+    }
+}
+
+impl Sampler for Stratified {
+    fn get_1d(&mut self) -> f32 {
+        let seed = self.next_seed();
+        let stratum = permutation_element(self.sample % self.samples, self.samples, seed);
+
+        (stratum as f32 + self.rng.rand_f32()) / self.samples as f32
+    }
+
+    fn get_2d(&mut self) -> Vector2f {
+        let seed = self.next_seed();
+
+        // sample counts that aren't square leave some cells empty
+        let n = (self.samples as f32).sqrt().ceil() as u32;
+        let cell = permutation_element(self.sample % (n * n), n * n, seed);
+
+        Vector2f::new(
+            ((cell % n) as f32 + self.rng.rand_f32()) / n as f32,
+            ((cell / n) as f32 + self.rng.rand_f32()) / n as f32,
+        )
+    }
+}
+
+/// Hash based Owen scrambling of the bits of `x`, "Practical Hash-based Owen Scrambling"
+/// (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+
+    x.reverse_bits()
+}
+
+/// Point `index` of the first two dimensions of the Sobol sequence
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let mut result = 0;
+    // the first dimension is the van der Corput sequence, the second one has the
+    // direction numbers v_i = v_(i-1) ^ (v_(i-1) >> 1)
+    let mut v = 1u32 << 31;
+
+    for bit in 0..32 {
+        if (index >> bit) & 1 != 0 {
+            result ^= if dimension == 0 { 1 << (31 - bit) } else { v };
+        }
+        v ^= v >> 1;
+    }
+
+    result
+}
+
+/// Owen-scrambled Sobol points. Only the first two dimensions of the sequence are used,
+/// every pair of dimensions shuffles the sample order differently so they don't line up
+/// with each other (Burley 2020)
+pub struct Sobol {
+    pixel_seed: u64,
+    sample: u32,
+    dimension: u32,
+}
+
+impl Sobol {
+    /// The shuffled sample index and a seed for scrambling the next dimension
+    fn next_index(&mut self) -> (u32, u32) {
+        self.dimension += 1;
+        let seed = hash(self.pixel_seed, self.dimension as u64) as u32;
+
+        (nested_uniform_scramble(self.sample, seed), seed)
+    }
+}
+
+impl Sampler for Sobol {
+    fn get_1d(&mut self) -> f32 {
+        let (index, seed) = self.next_index();
+        let x = nested_uniform_scramble(sobol(index, 0), hash(seed as u64, 0) as u32);
+
+        to_unit(x)
+    }
+
+    fn get_2d(&mut self) -> Vector2f {
+        let (index, seed) = self.next_index();
+        let x = nested_uniform_scramble(sobol(index, 0), hash(seed as u64, 0) as u32);
+        let y = nested_uniform_scramble(sobol(index, 1), hash(seed as u64, 1) as u32);
+
+        Vector2f::new(to_unit(x), to_unit(y))
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = 1.0;
+    let mut reversed = 0.0;
+
+    while index > 0 {
+        let next = index / base;
+        let digit = index - next * base;
+
+        reversed = reversed * base as f64 + digit as f64;
+        inv_base_n *= inv_base;
+        index = next;
+    }
+
+    (reversed * inv_base_n).min(1.0 - f64::EPSILON) as f32
+}
+
+/// Halton points, every dimension uses the next prime as its base. Each pixel shifts the
+/// points by a random offset (Cranley-Patterson rotation) so neighbouring pixels don't share
+/// the same pattern. Runs out of primes eventually and falls back to random numbers
+pub struct Halton {
+    rng: Rng,
+    pixel_seed: u64,
+    sample: u32,
+    dimension: u32,
+}
+
+impl Sampler for Halton {
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+
+        let Some(&base) = PRIMES.get(dimension) else {
+            return self.rng.rand_f32();
+        };
+
+        let offset = to_unit(hash(self.pixel_seed, dimension as u64) as u32);
+        let x = radical_inverse(base, self.sample) + offset;
+
+        if x >= 1.0 {
+            x - 1.0
+        } else {
+            x
+        }
+    }
+
+    fn get_2d(&mut self) -> Vector2f {
+        Vector2f::new(self.get_1d(), self.get_1d())
+    }
+}
+
+/// Uniform point on the unit disk, keeping the stratification of `u`. "A Low Distortion Map
+/// Between Disk and Square" (Shirley and Chiu 1997)
+pub fn sample_disk(u: Vector2f) -> Vector2f {
+    let offset = u * 2.0 - Vector2f::new(1.0, 1.0);
+    if offset.x == 0.0 && offset.y == 0.0 {
+        return Vector2f::zeros();
+    }
+
+    let (r, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, PI / 4.0 * (offset.y / offset.x))
+    } else {
+        (offset.y, PI / 2.0 - PI / 4.0 * (offset.x / offset.y))
+    };
+
+    Vector2f::new(r * theta.cos(), r * theta.sin())
+}
+
+/// Cosine weighted direction around +z, pdf cos(theta) / pi
+pub fn sample_cosine_hemisphere(u: Vector2f) -> Vector3f {
+    let d = sample_disk(u);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+
+    Vector3f::new(d.x, d.y, z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Sobol,
+        SamplerKind::Halton,
+    ];
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        let unit = |x: f32| (0.0..1.0).contains(&x);
+
+        for kind in KINDS {
+            for samples in [1, 7, 16, 1000] {
+                for pixel in 0..64 {
+                    for sample in [0, 1, samples / 2, samples - 1] {
+                        let mut sampler = kind.for_pixel(pixel, sample, samples, 7);
+
+                        // more dimensions than Halton has primes
+                        for _ in 0..40 {
+                            let x = sampler.get_1d();
+                            let p = sampler.get_2d();
+                            assert!(unit(x) && unit(p.x) && unit(p.y), "{:?} {} {}", kind, x, p);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_fills_every_stratum() {
+        let samples = 16;
+        let mut strata = [0; 16];
+        let mut cells = [0; 16];
+
+        for sample in 0..samples {
+            let mut sampler = SamplerKind::Stratified.for_pixel(3, sample, samples, 0);
+            strata[(sampler.get_1d() * 16.0) as usize] += 1;
+            let p = sampler.get_2d();
+            cells[(p.x * 4.0) as usize + 4 * (p.y * 4.0) as usize] += 1;
+        }
+
+        assert_eq!(strata, [1; 16]);
+        assert_eq!(cells, [1; 16]);
+    }
+
+    #[test]
+    fn same_sample_same_numbers() {
+        for kind in KINDS {
+            let mut a = kind.for_pixel(5, 3, 16, 1);
+            let mut b = kind.for_pixel(5, 3, 16, 1);
+            for _ in 0..10 {
+                assert_eq!(a.get_2d(), b.get_2d());
+            }
+        }
+    }
+}
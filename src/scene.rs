@@ -1,25 +1,28 @@
 use std::f32::consts::PI;
-use std::f32::EPSILON;
 
 use nalgebra::DMatrix;
 
-use crate::bsdf::{Glossy, Lambertian, BSDF, UP};
+use crate::aov::Aovs;
+use crate::bsdf::{Conductor, Dielectric, Glossy, Lambertian, BSDF};
 use crate::camera::Camera;
 use crate::geom::{normalize, BVHTriangle, BvhScene, Material, Object};
-use crate::rng::{rand_direction, rand_hemisphere};
-use crate::texture::{equirectangular, Texture};
-use crate::{bsdf, rad, Color, Matrix3f, Matrix4f, Ray, Vector3f};
+use crate::light::{power_heuristic, Light, LightSample, Lights};
+use crate::sampler::Sampler;
+use crate::texture::{equirectangular, equirectangular_direction, Texture, MIN_SIN_THETA};
+use crate::{Color, Matrix3f, Matrix4f, Point3f, Ray, Vector2f, Vector3f};
 
 pub struct Scene {
     pub camera: Camera,
     pub objects: Vec<Object>,
     pub env_map: DMatrix<Color>,
+    /// lights that aren't emissive objects, in world space
+    pub lights: Vec<Light>,
 
     pub bvh: Option<BvhScene>,
-}
-
-fn close_to_zero(val: f32) -> bool {
-    val.abs() < EPSILON
+    /// emissive triangles, the lights and the environment, filled in together with the BVH
+    pub light_sampler: Lights,
+    /// hash of the files the scene was loaded from, to tell which scene a checkpoint belongs to
+    pub source_hash: u64,
 }
 
 impl Scene {
@@ -28,7 +31,10 @@ impl Scene {
             camera,
             objects,
             env_map,
+            lights: Vec::new(),
             bvh: None,
+            light_sampler: Lights::default(),
+            source_hash: 0,
         }
     }
 
@@ -64,15 +70,188 @@ impl Scene {
         // }
     }
 
-    pub fn sample(&self, ray: &Ray, max_bounces: u32) -> Color {
-        assert!(self.bvh.is_some());
+    /// Solid angle pdf of `sample_env_direction` returning `direction`
+    fn env_pdf(&self, direction: Vector3f) -> f32 {
+        let world = self.camera.transform.matrix_f.transform_vector(&direction);
+        let uv = equirectangular(world);
+
+        // going from uv to the sphere stretches by 2pi in phi, pi in theta and sin(theta)
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta < MIN_SIN_THETA {
+            return 0.0;
+        }
+
+        self.light_sampler.env.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Pick a direction (in camera space) to look for light from the environment, importance
+    /// sampled by how bright the environment map is in that direction
+    fn sample_env_direction(&self, u: Vector2f) -> Option<(Vector3f, f32)> {
+        let (uv, uv_pdf) = self.light_sampler.env.sample(u.x, u.y);
+
+        // right at the poles the direction wouldn't map back to the same uv, leave those to
+        // bsdf sampling like env_pdf does
+        let sin_theta = (uv.y * PI).sin();
+        if uv_pdf <= 0.0 || sin_theta < MIN_SIN_THETA {
+            return None;
+        }
+
+        let world = equirectangular_direction(uv);
+        let direction = normalize(self.camera.transform.inv_matrix_f.transform_vector(&world));
+
+        Some((direction, uv_pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    /// Direct light from one randomly picked light source arriving at `point`, reflected
+    /// towards `reflected` by the bsdf. Weighted for combining with bsdf sampling.
+    /// `u_light` picks the light and `u` the point on it
+    fn sample_lights(
+        &self,
+        point: Point3f,
+        to_normal: &Matrix3f,
+        reflected: Vector3f,
+        bsdf: &dyn BSDF,
+        u_light: f32,
+        u: Vector2f,
+    ) -> Color {
         let bvh = self.bvh.as_ref().unwrap();
 
-        if max_bounces == 0 {
+        let env_probability = self.light_sampler.env_probability;
+
+        let light = if u_light < env_probability {
+            let Some((direction, pdf)) = self.sample_env_direction(u) else {
+                return Color::zeros();
+            };
+
+            LightSample {
+                direction,
+                distance: f32::INFINITY,
+                radiance: self.sample_env(&Ray::new(point, direction)),
+                pdf: pdf * env_probability,
+                delta: false,
+                light: None,
+            }
+        } else {
+            // stretch what's left of the number back to [0, 1) to pick the light with
+            let u_pick = (u_light - env_probability) / (1.0 - env_probability);
+
+            let Some(light) = self
+                .light_sampler
+                .sample(point, u_pick.min(1.0 - f32::EPSILON), u)
+            else {
+                return Color::zeros();
+            };
+
+            light
+        };
+        let LightSample {
+            direction,
+            distance,
+            radiance,
+            pdf: light_pdf,
+            delta,
+            light,
+        } = light;
+
+        let incedent = to_normal * direction;
+        let dot_component = incedent.z;
+        if dot_component <= 0.0 || light_pdf <= 0.0 || radiance == Color::zeros() {
             return Color::zeros();
         }
 
-        if let Some((dist, tri_idx)) = bvh.intersects(ray) {
+        let shadow_ray = Ray::new(point + direction * 1e-4, direction);
+        if bvh.occluded(&shadow_ray, distance - 2e-4)
+            || self.light_sampler.occluded(
+                shadow_ray.origin,
+                shadow_ray.direction,
+                distance - 2e-4,
+                light,
+            )
+        {
+            return Color::zeros();
+        }
+
+        let value = bsdf.value(incedent, reflected);
+        let weight = if delta {
+            // can't be hit by sampling the bsdf
+            1.0
+        } else {
+            power_heuristic(light_pdf, bsdf.pdf(incedent, reflected))
+        };
+
+        value.component_mul(&radiance) * (dot_component * weight / light_pdf)
+    }
+
+    /// Trace a path starting at `ray` for at most `max_bounces` segments and return the light
+    /// it carries back. Lights are sampled directly at every bounce and combined with the
+    /// bsdf samples that happen to hit them through multiple importance sampling
+    pub fn sample(&self, ray: &Ray, max_bounces: u32, sampler: &mut dyn Sampler) -> Color {
+        self.sample_aovs(ray, max_bounces, sampler).0
+    }
+
+    /// Like `sample`, but also fills in the AOVs for the camera ray
+    pub fn sample_aovs(
+        &self,
+        ray: &Ray,
+        max_bounces: u32,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, Aovs) {
+        assert!(self.bvh.is_some());
+        let bvh = self.bvh.as_ref().unwrap();
+
+        let mut ray = Ray::new(ray.origin, ray.direction);
+        let mut throughput = Color::repeat(1.0);
+        let mut radiance = Color::zeros();
+        let mut aovs = Aovs::default();
+        // whether the lighting goes into the diffuse or the specular AOV
+        let mut diffuse = false;
+
+        // pdf of the bsdf sample that produced `ray`, none for camera rays and delta bounces
+        // since lights can't be sampled for those
+        let mut bsdf_pdf: Option<f32> = None;
+
+        for bounce in 0..max_bounces {
+            // lights hit by chance get weighted against having been sampled directly
+            let mis = |light_pdf: f32| bsdf_pdf.map_or(1.0, |pdf| power_heuristic(pdf, light_pdf));
+
+            let hit = bvh.intersects(&ray);
+
+            // disk and rect lights aren't in the BVH
+            let closest = hit.map_or(f32::INFINITY, |(dist, _)| dist);
+            if let Some((index, _)) = self
+                .light_sampler
+                .intersect(ray.origin, ray.direction)
+                .filter(|&(_, dist)| dist < closest)
+            {
+                let light = &self.light_sampler.analytic[index];
+                let light_pdf = self
+                    .light_sampler
+                    .analytic_pdf(index, ray.origin, ray.direction);
+
+                let contribution =
+                    throughput.component_mul(&light.emitted(ray.direction)) * mis(light_pdf);
+                radiance += contribution;
+                aovs.add_light(contribution, bounce, diffuse);
+                break;
+            }
+
+            let Some((dist, tri_idx)) = hit else {
+                let env_pdf = self.light_sampler.env_probability * self.env_pdf(ray.direction);
+                let contribution = throughput.component_mul(&self.sample_env(&ray)) * mis(env_pdf);
+                radiance += contribution;
+                aovs.add_light(contribution, bounce, diffuse);
+
+                for (index, emitted) in self.light_sampler.distant(ray.direction) {
+                    let light_pdf =
+                        self.light_sampler
+                            .analytic_pdf(index, ray.origin, ray.direction);
+                    let contribution = throughput.component_mul(&emitted) * mis(light_pdf);
+                    radiance += contribution;
+                    aovs.add_light(contribution, bounce, diffuse);
+                }
+                break;
+            };
+
             let new_origin = ray.origin + ray.direction * dist;
 
             let material = &bvh.materials[tri_idx];
@@ -85,85 +264,135 @@ impl Scene {
 
             // assert!(normal.norm() - 1.0 < 1e-4);
 
-            let basis_z = normal.normalize();
-            let basis_y = Vector3f::new(1.0, 0.0, 0.0).cross(&basis_z).normalize();
-            let basis_x = basis_y.cross(&basis_z).normalize();
+            let backface = ray.direction.dot(&normal) > 0.0;
 
-            let from_normal = Matrix3f::from_columns(&[basis_x, basis_y, basis_z]);
-            let to_normal = from_normal.transpose();
+            if bounce == 0 {
+                let camera = &self.camera;
+                let facing = if backface { -normal } else { normal };
 
-            // ray.direction.z
+                aovs.depth = dist;
+                aovs.position = camera.transform.matrix_f.transform_point(&new_origin).coords;
+                aovs.camera_position = camera.bvh_to_view(new_origin.coords);
+                aovs.normal = normalize(camera.transform.matrix_f.transform_vector(&facing));
+                aovs.albedo = material.albedo();
+                aovs.object_id = bvh.object_ids[tri_idx];
+                aovs.material_id = bvh.material_ids[tri_idx];
+
+                diffuse = matches!(material, Material::Diffuse(_));
+            }
 
-            // specular reflection
-            // let dir = ray.direction - 2.0 * ray.direction.dot(&normal) * normal;
-            // let ray = Ray::new(new_origin, dir);
+            if let Material::Emissive { .. } = material {
+                let light_pdf = self.light_sampler.pdf(tri_idx, ray.origin, new_origin);
 
-            if ray.direction.dot(&normal) > 0.0 {
+                // lights don't reflect anything
+                let contribution =
+                    throughput.component_mul(&material.emission(backface)) * mis(light_pdf);
+                radiance += contribution;
+                aovs.add_light(contribution, bounce, diffuse);
+                break;
+            }
+
+            if backface && !material.is_transmissive() {
                 // backface culling
-                return Color::zeros();
+                break;
             }
 
-            // diffuse reflection
-            // let dir = normalize(normal + rand_direction());
-            // let ray = Ray {
-            //     origin: new_origin + dir * 1e-4,
-            //     direction: dir,
-            //     inv_direction: Vector3f::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z),
-            // };
+            // shade from whichever side the ray came from
+            let normal = if backface { -normal } else { normal };
 
-            // self.sample(&ray, max_bounces - 1)
+            let basis_z = normal.normalize();
+            let basis_y = Vector3f::new(1.0, 0.0, 0.0).cross(&basis_z).normalize();
+            let basis_x = basis_y.cross(&basis_z).normalize();
+
+            let from_normal = Matrix3f::from_columns(&[basis_x, basis_y, basis_z]);
+            let to_normal = from_normal.transpose();
 
             // bsdf based rendering
             let bsdf: Box<dyn BSDF> = match material {
                 Material::Diffuse(albedo) => Box::new(Lambertian { albedo: *albedo }),
                 Material::Glossy => Box::new(Glossy {}),
+                Material::Dielectric { ior, tint } => Box::new(Dielectric {
+                    eta: if backface { 1.0 / ior } else { *ior },
+                    tint: *tint,
+                }),
+                Material::Conductor {
+                    roughness,
+                    eta,
+                    k,
+                    tint,
+                } => Box::new(Conductor::new(*roughness, *eta, *k, *tint)),
+                Material::Emissive { .. } => unreachable!(),
             };
-            // let bsdf = Glossy {};
-            // let bsdf = Lambertian { albedo: 0.9 };
 
             // enter normal space
             let reflected = to_normal * ray.direction;
-            let incedent = bsdf.sample(reflected);
 
-            let dot_component = incedent.dot(&UP); // dot product w/ normal in rendering equation
-            let pdf = bsdf.pdf(incedent, reflected);
-            let value = bsdf.value(incedent, reflected);
-
-            if dot_component <= EPSILON {
-                // this ray contributes nothing
-                return Color::zeros();
+            // every bounce takes the same dimensions, even if it doesn't use them, so the
+            // sampler's points stay well distributed along the whole path
+            let u_light = sampler.get_1d();
+            let u_light_point = sampler.get_2d();
+            let u_lobe = sampler.get_1d();
+            let u_bsdf = sampler.get_2d();
+
+            if !bsdf.is_delta() {
+                let direct = self.sample_lights(
+                    new_origin,
+                    &to_normal,
+                    reflected,
+                    bsdf.as_ref(),
+                    u_light,
+                    u_light_point,
+                );
+                let contribution = throughput.component_mul(&direct);
+                radiance += contribution;
+                aovs.add_light(contribution, bounce + 1, diffuse);
             }
 
-            let dir = normalize(from_normal * incedent);
+            let Some(bsdf_sample) = bsdf.sample(reflected, u_lobe, u_bsdf) else {
+                // this path contributes nothing else
+                break;
+            };
+
+            // final rendering equation f * L * (dot) / pdf, already folded into the weight
+            throughput.component_mul_assign(&bsdf_sample.weight);
+            bsdf_pdf = (!bsdf_sample.delta).then_some(bsdf_sample.pdf);
+
             // leave normal space
+            let dir = normalize(from_normal * bsdf_sample.incedent);
 
-            let ray = Ray {
+            ray = Ray {
                 origin: new_origin + dir * 1e-4,
                 direction: dir,
                 inv_direction: Vector3f::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z),
             };
+        }
 
-            // L component of rendering equation
-            let output = self.sample(&ray, max_bounces - 1);
-
-            // final rendering equation f * L * (dot) / pdf
-            let coeff = if close_to_zero(value) && close_to_zero(pdf) {
-                // this means that pdf is zero everywhere except at one point (like in the case of
-                // glossy bsdfs) so we can just return the value at that point
-
-                1.0
-            } else {
-                dot_component * value / pdf
-            };
+        (radiance, aovs)
+    }
 
-            output * coeff
-        } else {
-            self.sample_env(ray)
+    /// Every material name once, in the order they first show up. Material IDs index into this
+    /// starting at 1
+    pub fn material_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for object in &self.objects {
+            if !names.contains(&object.material_name) {
+                names.push(object.material_name.clone());
+            }
         }
+
+        names
     }
 
     pub fn build_bvh(&mut self) {
-        self.bvh = Some(self.bvh());
+        let bvh = self.bvh();
+        let lights = self
+            .lights
+            .iter()
+            .map(|light| light.transformed(&self.camera.transform.inv_matrix_f))
+            .collect();
+
+        self.light_sampler = Lights::new(&bvh, &self.env_map, lights);
+        self.bvh = Some(bvh);
     }
 
     fn bvh(&self) -> BvhScene {
@@ -172,8 +401,19 @@ impl Scene {
         let mut triangles = Vec::new();
         let mut normals = Vec::new();
         let mut materials = Vec::new();
+        let mut object_ids = Vec::new();
+        let mut material_ids = Vec::new();
+
+        let material_names = self.material_names();
+
+        for (index, object) in self.objects.iter().enumerate() {
+            // starting at 1 like objects
+            let material_id = material_names
+                .iter()
+                .position(|name| *name == object.material_name)
+                .unwrap()
+                + 1;
 
-        for object in &self.objects {
             let object_to_world = object.transform.matrix;
             let object_to_camera = world_to_camera * object_to_world;
 
@@ -217,11 +457,13 @@ impl Scene {
                 normals.push((a, b, c));
             }
 
-            for triangle in &object.mesh.triangles {
+            for _ in &object.mesh.triangles {
                 materials.push(object.material);
+                object_ids.push(index as u32 + 1);
+                material_ids.push(material_id as u32);
             }
         }
 
-        BvhScene::new(triangles, normals, materials)
+        BvhScene::new(triangles, normals, materials, object_ids, material_ids)
     }
 }
@@ -0,0 +1,1055 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use nalgebra::DMatrix;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::camera::{perspective, Camera, Exposure, Lens, Projection, UP};
+use crate::checkpoint::Fnv;
+use crate::color::{DisplayTransform, Tonemap, Transfer};
+use crate::denoise::Denoiser;
+use crate::filter::{Filter, FilterKind};
+use crate::geom::{Material, Transform};
+use crate::light::{Falloff, Light};
+use crate::objfile::load_obj;
+use crate::render::{Adaptive, RenderSettings};
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::texture::load_exr;
+use crate::tile::{Tile, Tiles};
+use crate::{rad, Color, Point3d, Quaternion, Vector3d, Vector3f};
+
+// Everything in here mirrors the layout of the scene file one to one, the conversion into the
+// actual scene types happens in `load_scene` so that errors can point back at the file
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: CameraDesc,
+    #[serde(default)]
+    render: RenderDesc,
+    #[serde(default)]
+    display: DisplayDesc,
+    #[serde(default)]
+    environment: EnvironmentDesc,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightDesc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    position: Spanned<[f64; 3]>,
+    /// defaults to the origin
+    look_at: Option<Spanned<[f64; 3]>>,
+    #[serde(default)]
+    projection: ProjectionDesc,
+    /// field of view in degrees, vertical for perspective cameras and across the image circle
+    /// for fisheyes. Defaults to 50 and 180 degrees
+    fov: Option<Spanned<f64>>,
+    /// how much of the scene an orthographic camera sees vertically, in scene units. Defaults
+    /// to 2
+    ortho_height: Option<Spanned<f32>>,
+    /// distance between the eyes of a stereo camera
+    #[serde(default = "default_ipd")]
+    ipd: f32,
+    /// radius of the lens aperture in scene units, or `f_stop` to derive it from the field of
+    /// view with a full frame sensor. Leave both out for a pinhole
+    aperture: Option<Spanned<f32>>,
+    f_stop: Option<Spanned<f32>>,
+    /// defaults to the distance to `look_at`
+    focus_distance: Option<f32>,
+    /// exposure like a real camera, iso and shutter (in seconds) together with f_stop. Or
+    /// directly as ev100. Leave them out to use the radiance as it is
+    iso: Option<Spanned<f32>>,
+    shutter: Option<Spanned<f32>>,
+    ev100: Option<Spanned<f32>>,
+    /// color temperature in Kelvin that comes out white
+    white_balance: Option<Spanned<f32>>,
+    /// number of aperture blades for polygonal bokeh, 0 for a round aperture
+    #[serde(default)]
+    blades: u32,
+    /// in degrees
+    #[serde(default)]
+    blade_rotation: f32,
+}
+
+/// Height of a full frame sensor in meters, for turning an f-stop into an aperture size
+const SENSOR_HEIGHT: f32 = 0.024;
+
+fn default_ipd() -> f32 {
+    0.064
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ProjectionDesc {
+    #[default]
+    Perspective,
+    Orthographic,
+    FisheyeEquidistant,
+    FisheyeEquisolid,
+    Equirectangular,
+    Stereo,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct RenderDesc {
+    /// defaults to 1920x1080
+    resolution: Option<Spanned<[usize; 2]>>,
+    samples: u32,
+    max_bounces: u32,
+    seed: u32,
+    /// independent, stratified, sobol or halton
+    sampler: Option<Spanned<String>>,
+    /// box, tent, gaussian, mitchell or blackman-harris
+    filter: Option<Spanned<String>>,
+    /// in pixels, defaults to one that suits the filter
+    filter_radius: Option<Spanned<f32>>,
+    /// write depth, normals, IDs and lighting passes as extra EXR layers
+    aovs: bool,
+    /// write Cryptomatte ID mattes for objects and materials into the EXR
+    cryptomatte: bool,
+    /// denoise the image at the end
+    denoise: bool,
+    /// stop sampling pixels whose noise drops below this, `samples` becomes the most they get
+    adaptive_threshold: Option<Spanned<f32>>,
+    /// samples every pixel gets before adaptive sampling can stop it
+    min_samples: Option<Spanned<u32>>,
+    /// render in square tiles of this many pixels instead of passes over the whole image
+    tile_size: Option<Spanned<usize>>,
+    /// scanline, spiral or hilbert
+    tile_order: Option<Spanned<String>>,
+    /// only render the pixels in [x, y, width, height]
+    region: Option<Spanned<[usize; 4]>>,
+}
+
+impl Default for RenderDesc {
+    fn default() -> Self {
+        let settings = RenderSettings::default();
+
+        Self {
+            resolution: None,
+            samples: settings.samples,
+            max_bounces: settings.max_bounces,
+            seed: settings.seed,
+            sampler: None,
+            filter: None,
+            filter_radius: None,
+            aovs: settings.aovs,
+            cryptomatte: settings.cryptomatte,
+            denoise: settings.denoise.is_some(),
+            adaptive_threshold: None,
+            min_samples: None,
+            tile_size: None,
+            tile_order: None,
+            region: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct DisplayDesc {
+    /// linear, reinhard, hable, aces or agx
+    tonemap: Option<Spanned<String>>,
+    /// luminance that maps to white with reinhard
+    white: Option<Spanned<f32>>,
+    /// in stops
+    exposure: f32,
+    /// srgb or rec709
+    transfer: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    hdri: Option<Spanned<String>>,
+    color: Option<Spanned<ColorDesc>>,
+    #[serde(default = "default_strength")]
+    strength: f32,
+}
+
+impl Default for EnvironmentDesc {
+    fn default() -> Self {
+        Self {
+            hdri: None,
+            color: None,
+            strength: default_strength(),
+        }
+    }
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum ColorDesc {
+    Gray(f32),
+    Rgb([f32; 3]),
+}
+
+impl From<ColorDesc> for Color {
+    fn from(desc: ColorDesc) -> Self {
+        match desc {
+            ColorDesc::Gray(v) => Color::new(v, v, v),
+            ColorDesc::Rgb([r, g, b]) => Color::new(r, g, b),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum MaterialDesc {
+    Diffuse {
+        #[serde(default = "white")]
+        albedo: ColorDesc,
+    },
+    Glossy,
+    #[serde(alias = "glass")]
+    Dielectric {
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default = "white")]
+        tint: ColorDesc,
+    },
+    /// either a preset `metal` or its complex index of refraction as `eta` and `k`
+    #[serde(alias = "metal")]
+    Conductor {
+        #[serde(default)]
+        roughness: f32,
+        #[serde(default)]
+        metal: Metal,
+        eta: Option<[f32; 3]>,
+        k: Option<[f32; 3]>,
+        #[serde(default = "white")]
+        tint: ColorDesc,
+    },
+    /// light source
+    #[serde(alias = "light")]
+    Emissive {
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+        #[serde(default)]
+        two_sided: bool,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Metal {
+    #[default]
+    Aluminium,
+    Chromium,
+    Copper,
+    Gold,
+    Iron,
+    Silver,
+}
+
+impl Metal {
+    /// Complex index of refraction (eta, k) at roughly the R, G and B wavelengths
+    fn ior(self) -> ([f32; 3], [f32; 3]) {
+        match self {
+            Metal::Aluminium => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+            Metal::Chromium => ([3.105, 3.183, 2.336], [3.335, 3.331, 3.224]),
+            Metal::Copper => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+            Metal::Gold => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+            Metal::Iron => ([2.911, 2.950, 2.585], [3.089, 2.932, 2.767]),
+            Metal::Silver => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+        }
+    }
+}
+
+fn white() -> ColorDesc {
+    ColorDesc::Gray(1.0)
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+impl MaterialDesc {
+    fn to_material(self) -> anyhow::Result<Material> {
+        let material = match self {
+            MaterialDesc::Diffuse { albedo } => Material::Diffuse(albedo.into()),
+            MaterialDesc::Glossy => Material::Glossy,
+            MaterialDesc::Dielectric { ior, tint } => Material::Dielectric {
+                ior,
+                tint: tint.into(),
+            },
+            MaterialDesc::Conductor {
+                roughness,
+                metal,
+                eta,
+                k,
+                tint,
+            } => {
+                // half of one and half of the preset would be neither metal
+                let (eta, k) = match (eta, k) {
+                    (Some(eta), Some(k)) => (eta, k),
+                    (None, None) => metal.ior(),
+                    _ => bail!(
+                        "a conductor needs both eta and k, or neither to use the `metal` preset"
+                    ),
+                };
+
+                Material::Conductor {
+                    roughness,
+                    eta: Color::from(eta),
+                    k: Color::from(k),
+                    tint: tint.into(),
+                }
+            }
+            MaterialDesc::Emissive {
+                color,
+                strength,
+                two_sided,
+            } => Material::Emissive {
+                color: color.into(),
+                strength,
+                two_sided,
+            },
+        };
+
+        Ok(material)
+    }
+}
+
+/// Angles are in degrees, `direction` is the way the light shines in
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum LightDesc {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+        #[serde(default)]
+        falloff: FalloffDesc,
+    },
+    Spot {
+        position: [f32; 3],
+        #[serde(default = "down")]
+        direction: [f32; 3],
+        /// full opening angle of the cone
+        #[serde(default = "default_spot_angle")]
+        angle: f32,
+        /// fraction of the cone over which the light fades out
+        #[serde(default = "default_spot_blend")]
+        blend: f32,
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+        #[serde(default)]
+        falloff: FalloffDesc,
+    },
+    /// strength is the irradiance on a surface facing the light
+    #[serde(alias = "sun")]
+    Directional {
+        #[serde(default = "down")]
+        direction: [f32; 3],
+        /// angular diameter, zero for perfectly sharp shadows
+        #[serde(default = "default_sun_angle")]
+        angle: f32,
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+    },
+    Disk {
+        position: [f32; 3],
+        #[serde(default = "down")]
+        direction: [f32; 3],
+        #[serde(default = "default_radius")]
+        radius: f32,
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+        #[serde(default)]
+        two_sided: bool,
+    },
+    Rect {
+        position: [f32; 3],
+        #[serde(default = "down")]
+        direction: [f32; 3],
+        /// width and height, the width stays level with the ground
+        #[serde(default = "default_rect_size")]
+        size: [f32; 2],
+        #[serde(default = "white")]
+        color: ColorDesc,
+        #[serde(default = "default_strength")]
+        strength: f32,
+        #[serde(default)]
+        two_sided: bool,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum FalloffDesc {
+    #[default]
+    InverseSquare,
+    Linear,
+    Constant,
+}
+
+impl From<FalloffDesc> for Falloff {
+    fn from(desc: FalloffDesc) -> Self {
+        match desc {
+            FalloffDesc::InverseSquare => Falloff::InverseSquare,
+            FalloffDesc::Linear => Falloff::Linear,
+            FalloffDesc::Constant => Falloff::Constant,
+        }
+    }
+}
+
+fn down() -> [f32; 3] {
+    [0.0, 0.0, -1.0]
+}
+
+fn default_spot_angle() -> f32 {
+    45.0
+}
+
+fn default_spot_blend() -> f32 {
+    0.15
+}
+
+fn default_sun_angle() -> f32 {
+    0.53
+}
+
+fn default_radius() -> f32 {
+    1.0
+}
+
+fn default_rect_size() -> [f32; 2] {
+    [1.0, 1.0]
+}
+
+impl LightDesc {
+    fn to_light(&self) -> anyhow::Result<Light> {
+        let direction = |d: [f32; 3]| {
+            Vector3f::from(d)
+                .try_normalize(1e-6)
+                .ok_or_else(|| anyhow!("light direction can't be zero"))
+        };
+        let positive = |name: &str, v: f32| {
+            if v > 0.0 {
+                Ok(v)
+            } else {
+                Err(anyhow!("light {} has to be positive", name))
+            }
+        };
+
+        let light = match *self {
+            LightDesc::Point {
+                position,
+                color,
+                strength,
+                falloff,
+            } => Light::Point {
+                position: position.into(),
+                intensity: Color::from(color) * strength,
+                falloff: falloff.into(),
+            },
+            LightDesc::Spot {
+                position,
+                direction: spot_direction,
+                angle,
+                blend,
+                color,
+                strength,
+                falloff,
+            } => {
+                if !(angle > 0.0 && angle <= 180.0) {
+                    bail!("spot light angle has to be between 0 and 180 degrees");
+                }
+                if !(0.0..=1.0).contains(&blend) {
+                    bail!("spot light blend has to be between 0 and 1");
+                }
+                let half_angle = (angle / 2.0).to_radians();
+
+                Light::Spot {
+                    position: position.into(),
+                    direction: direction(spot_direction)?,
+                    intensity: Color::from(color) * strength,
+                    falloff: falloff.into(),
+                    cos_outer: half_angle.cos(),
+                    cos_inner: (half_angle * (1.0 - blend)).cos(),
+                }
+            }
+            LightDesc::Directional {
+                direction: sun_direction,
+                angle,
+                color,
+                strength,
+            } => {
+                if !(0.0..180.0).contains(&angle) {
+                    bail!("directional light angle has to be between 0 and 180 degrees");
+                }
+
+                Light::Directional {
+                    direction: direction(sun_direction)?,
+                    irradiance: Color::from(color) * strength,
+                    cos_max: (angle / 2.0).to_radians().cos(),
+                }
+            }
+            LightDesc::Disk {
+                position,
+                direction: normal,
+                radius,
+                color,
+                strength,
+                two_sided,
+            } => Light::Disk {
+                center: position.into(),
+                normal: direction(normal)?,
+                radius: positive("radius", radius)?,
+                radiance: Color::from(color) * strength,
+                two_sided,
+            },
+            LightDesc::Rect {
+                position,
+                direction: normal,
+                size: [width, height],
+                color,
+                strength,
+                two_sided,
+            } => {
+                let normal = direction(normal)?;
+                let up = UP.cast::<f32>();
+
+                // keep the width horizontal, unless the light is pointing straight up or down
+                let u = normal
+                    .cross(&up)
+                    .try_normalize(1e-6)
+                    .unwrap_or(Vector3f::new(1.0, 0.0, 0.0));
+                let v = normal.cross(&u);
+
+                Light::Rect {
+                    center: position.into(),
+                    u: u * positive("width", width)? / 2.0,
+                    v: v * positive("height", height)? / 2.0,
+                    radiance: Color::from(color) * strength,
+                    two_sided,
+                }
+            }
+        };
+
+        Ok(light)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScaleDesc {
+    Uniform(f64),
+    Axes([f64; 3]),
+}
+
+impl Default for ScaleDesc {
+    fn default() -> Self {
+        ScaleDesc::Uniform(1.0)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    name: Option<String>,
+    mesh: Spanned<String>,
+    #[serde(default)]
+    position: [f64; 3],
+    /// euler angles (roll, pitch, yaw) in degrees
+    #[serde(default)]
+    rotation: [f64; 3],
+    #[serde(default)]
+    scale: ScaleDesc,
+    /// either the name of an entry in `materials` or an inline material table
+    material: Spanned<toml::Value>,
+}
+
+fn line_of(src: &str, span: Range<usize>) -> usize {
+    src[..span.start].matches('\n').count() + 1
+}
+
+/// Load a scene description file, see `scene.toml` for an example.
+/// Relative paths inside the file are resolved against the directory the file is in.
+pub fn load_scene(path: impl AsRef<Path>) -> anyhow::Result<(Scene, RenderSettings)> {
+    let path = path.as_ref();
+    let src = fs::read_to_string(path)
+        .with_context(|| format!("failed to read scene file {}", path.display()))?;
+
+    // everything the scene is loaded from goes into the hash, see `checkpoint::render_hash`
+    let mut source_hash = Fnv::default();
+    source_hash.write(src.as_bytes());
+
+    let desc: SceneFile =
+        toml::from_str(&src).with_context(|| format!("invalid scene file {}", path.display()))?;
+
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let resolve = |p: &str| -> PathBuf { base_dir.join(p) };
+
+    // attach a line number to errors that are only found after parsing
+    let at = |span: Range<usize>| {
+        let line = line_of(&src, span);
+        move || format!("{}:{}", path.display(), line)
+    };
+
+    let mut objects = Vec::with_capacity(desc.objects.len());
+    for object_desc in &desc.objects {
+        let mesh_path = resolve(object_desc.mesh.get_ref());
+
+        let material_name = match object_desc.material.get_ref() {
+            toml::Value::String(name) => Some(name.clone()),
+            _ => None,
+        };
+        // named materials report their problems at their own table
+        let (material, span) = match object_desc.material.get_ref() {
+            toml::Value::String(name) => {
+                let material = desc
+                    .materials
+                    .get(name)
+                    .ok_or_else(|| anyhow!("unknown material '{}'", name))
+                    .with_context(at(object_desc.material.span()))?;
+                (*material.get_ref(), material.span())
+            }
+            value => (
+                MaterialDesc::deserialize(value.clone())
+                    .with_context(at(object_desc.material.span()))?,
+                object_desc.material.span(),
+            ),
+        };
+        let material = material.to_material().with_context(at(span))?;
+
+        let mut object = load_obj(&mesh_path.to_string_lossy(), material)
+            .with_context(|| format!("failed to load mesh {}", mesh_path.display()))
+            .with_context(at(object_desc.mesh.span()))?;
+        source_hash.write(&fs::read(&mesh_path)?);
+
+        let [x, y, z] = object_desc.position;
+        let [roll, pitch, yaw] = object_desc.rotation;
+        let scale = match object_desc.scale {
+            ScaleDesc::Uniform(s) => Vector3d::new(s, s, s),
+            ScaleDesc::Axes([x, y, z]) => Vector3d::new(x, y, z),
+        };
+
+        object.transform = Transform::new(
+            Point3d::new(x, y, z),
+            Quaternion::from_euler_angles(rad(roll), rad(pitch), rad(yaw)),
+            scale,
+        );
+
+        if let Some(name) = &object_desc.name {
+            object.name = name.clone();
+        }
+        // inline materials are named after their object
+        object.material_name = material_name.unwrap_or(object.name.clone());
+
+        objects.push(object);
+    }
+
+    let lights = desc
+        .lights
+        .iter()
+        .map(|light| light.get_ref().to_light().with_context(at(light.span())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let env = &desc.environment;
+    let env_map = match (&env.hdri, &env.color) {
+        (Some(_), Some(color)) => {
+            return Err(anyhow!(
+                "environment can have either an hdri or a color, not both"
+            ))
+            .with_context(at(color.span()))
+        }
+        (Some(hdri), None) => {
+            let hdri_path = resolve(hdri.get_ref());
+            let env_map = load_exr(&hdri_path)
+                .with_context(|| format!("failed to load hdri {}", hdri_path.display()))
+                .with_context(at(hdri.span()))?;
+            source_hash.write(&fs::read(&hdri_path)?);
+
+            env_map
+        }
+        (None, color) => {
+            let color = color
+                .as_ref()
+                .map_or(Color::zeros(), |c| (*c.get_ref()).into());
+            DMatrix::from_element(1, 1, color)
+        }
+    };
+    let env_map = env_map.map(|c| c * env.strength);
+
+    let [width, height] = match &desc.render.resolution {
+        Some(resolution) if resolution.get_ref().contains(&0) => {
+            return Err(anyhow!("resolution must be non-zero")).with_context(at(resolution.span()))
+        }
+        Some(resolution) => *resolution.get_ref(),
+        None => [1920, 1080],
+    };
+    let aspect = width as f32 / height as f32;
+
+    let camera_desc = &desc.camera;
+    let position = Point3d::from(*camera_desc.position.get_ref());
+    let look_at = camera_desc
+        .look_at
+        .as_ref()
+        .map_or([0.0; 3], |l| *l.get_ref());
+    let look_at = Point3d::from(look_at);
+
+    // there's no way to orient the camera around the view direction otherwise
+    let view = look_at - position;
+    if view.norm() == 0.0 || view.normalize().cross(&UP).norm() < 1e-6 {
+        let message = "the camera can't look at its own position or straight up or down";
+        let span = match &camera_desc.look_at {
+            Some(look_at) => look_at.span(),
+            None => camera_desc.position.span(),
+        };
+        return Err(anyhow!(message)).with_context(at(span));
+    }
+
+    let fov = match (&camera_desc.fov, camera_desc.projection) {
+        (Some(fov), _) if *fov.get_ref() <= 0.0 || *fov.get_ref() > 360.0 => {
+            return Err(anyhow!("fov has to be between 0 and 360 degrees"))
+                .with_context(at(fov.span()))
+        }
+        (Some(fov), ProjectionDesc::Perspective) if *fov.get_ref() >= 180.0 => {
+            return Err(anyhow!(
+                "a perspective camera needs a fov under 180 degrees"
+            ))
+            .with_context(at(fov.span()))
+        }
+        (Some(fov), _) => *fov.get_ref(),
+        (None, ProjectionDesc::FisheyeEquidistant | ProjectionDesc::FisheyeEquisolid) => 180.0,
+        (None, _) => 50.0,
+    };
+
+    let mut camera = Camera::new(
+        width,
+        height,
+        Transform::new(
+            position,
+            Quaternion::look_at_rh(&view, &UP),
+            Vector3d::new(1.0, 1.0, 1.0),
+        ),
+        perspective(rad(fov.min(179.0)) as f32, aspect),
+    );
+
+    camera.kind = match camera_desc.projection {
+        ProjectionDesc::Perspective => Projection::Perspective,
+        ProjectionDesc::Orthographic => match &camera_desc.ortho_height {
+            Some(height) if *height.get_ref() <= 0.0 => {
+                return Err(anyhow!("ortho_height has to be positive"))
+                    .with_context(at(height.span()))
+            }
+            Some(height) => Projection::Orthographic {
+                height: *height.get_ref(),
+            },
+            None => Projection::Orthographic { height: 2.0 },
+        },
+        ProjectionDesc::FisheyeEquidistant => Projection::FisheyeEquidistant {
+            fov: rad(fov) as f32,
+        },
+        ProjectionDesc::FisheyeEquisolid => Projection::FisheyeEquisolid {
+            fov: rad(fov) as f32,
+        },
+        ProjectionDesc::Equirectangular => Projection::Equirectangular,
+        ProjectionDesc::Stereo => Projection::Stereo {
+            ipd: camera_desc.ipd,
+        },
+    };
+
+    let focal_length = SENSOR_HEIGHT / 2.0 / (rad(fov.min(179.0)) as f32 / 2.0).tan();
+    let aperture_radius = match (&camera_desc.aperture, &camera_desc.f_stop) {
+        (Some(_), Some(f_stop)) => {
+            return Err(anyhow!(
+                "camera can have either an aperture or an f_stop, not both"
+            ))
+            .with_context(at(f_stop.span()))
+        }
+        (Some(radius), None) if *radius.get_ref() < 0.0 => {
+            return Err(anyhow!("aperture can't be negative")).with_context(at(radius.span()))
+        }
+        (Some(radius), None) => *radius.get_ref(),
+        (None, Some(f_stop)) if *f_stop.get_ref() <= 0.0 => {
+            return Err(anyhow!("f_stop has to be positive")).with_context(at(f_stop.span()))
+        }
+        (None, Some(f_stop)) => focal_length / *f_stop.get_ref() / 2.0,
+        (None, None) => 0.0,
+    };
+
+    camera.exposure = match (&camera_desc.ev100, &camera_desc.iso, &camera_desc.shutter) {
+        (Some(ev100), None, None) => Exposure::Ev100(*ev100.get_ref()),
+        (None, Some(iso), Some(shutter)) => {
+            let Some(f_stop) = &camera_desc.f_stop else {
+                return Err(anyhow!("iso and shutter need an f_stop too"))
+                    .with_context(at(iso.span()));
+            };
+            for value in [iso, shutter] {
+                if *value.get_ref() <= 0.0 {
+                    return Err(anyhow!("iso and shutter have to be positive"))
+                        .with_context(at(value.span()));
+                }
+            }
+            Exposure::Manual {
+                iso: *iso.get_ref(),
+                shutter: *shutter.get_ref(),
+                f_stop: *f_stop.get_ref(),
+            }
+        }
+        (None, None, None) => Exposure::Raw,
+        // point at whichever one doesn't belong
+        (Some(given), _, _) | (None, Some(given), None) | (None, None, Some(given)) => {
+            return Err(anyhow!(
+                "camera exposure is either ev100 or iso, shutter and f_stop"
+            ))
+            .with_context(at(given.span()))
+        }
+    };
+
+    camera.white_balance = match &camera_desc.white_balance {
+        Some(kelvin) if *kelvin.get_ref() <= 0.0 => {
+            return Err(anyhow!("white_balance has to be positive")).with_context(at(kelvin.span()))
+        }
+        kelvin => kelvin.as_ref().map(|k| *k.get_ref()),
+    };
+
+    camera.lens = Lens {
+        aperture_radius,
+        focus_distance: camera_desc
+            .focus_distance
+            .unwrap_or((look_at - position).norm() as f32),
+        blades: camera_desc.blades,
+        blade_rotation: camera_desc.blade_rotation.to_radians(),
+    };
+
+    let sampler = match &desc.render.sampler {
+        Some(sampler) => sampler
+            .get_ref()
+            .parse()
+            .map_err(|e: String| anyhow!(e))
+            .with_context(at(sampler.span()))?,
+        None => SamplerKind::default(),
+    };
+
+    let filter_kind = match &desc.render.filter {
+        Some(filter) => filter
+            .get_ref()
+            .parse()
+            .map_err(|e: String| anyhow!(e))
+            .with_context(at(filter.span()))?,
+        None => FilterKind::default(),
+    };
+    let filter_radius = match &desc.render.filter_radius {
+        Some(radius) if *radius.get_ref() <= 0.0 => {
+            return Err(anyhow!("filter radius has to be positive")).with_context(at(radius.span()))
+        }
+        Some(radius) => *radius.get_ref(),
+        None => filter_kind.default_radius(),
+    };
+
+    let display = &desc.display;
+    let mut tonemap = match &display.tonemap {
+        Some(tonemap) => tonemap
+            .get_ref()
+            .parse()
+            .map_err(|e: String| anyhow!(e))
+            .with_context(at(tonemap.span()))?,
+        None => Tonemap::default(),
+    };
+    if let Some(white) = &display.white {
+        match &mut tonemap {
+            Tonemap::Reinhard { white: w } if *white.get_ref() > 0.0 => *w = *white.get_ref(),
+            Tonemap::Reinhard { .. } => {
+                return Err(anyhow!("white has to be positive")).with_context(at(white.span()))
+            }
+            _ => {
+                return Err(anyhow!("white only works with the reinhard tonemapper"))
+                    .with_context(at(white.span()))
+            }
+        }
+    }
+    let transfer = match &display.transfer {
+        Some(transfer) => transfer
+            .get_ref()
+            .parse()
+            .map_err(|e: String| anyhow!(e))
+            .with_context(at(transfer.span()))?,
+        None => Transfer::default(),
+    };
+
+    let adaptive = match (&desc.render.adaptive_threshold, &desc.render.min_samples) {
+        (Some(threshold), _) if *threshold.get_ref() <= 0.0 => {
+            return Err(anyhow!("adaptive_threshold has to be positive"))
+                .with_context(at(threshold.span()))
+        }
+        (Some(threshold), min_samples) => Some(Adaptive {
+            threshold: *threshold.get_ref(),
+            min_samples: min_samples
+                .as_ref()
+                .map_or(Adaptive::default().min_samples, |min| *min.get_ref()),
+        }),
+        (None, Some(min_samples)) => {
+            return Err(anyhow!("min_samples needs adaptive_threshold"))
+                .with_context(at(min_samples.span()))
+        }
+        (None, None) => None,
+    };
+
+    let tiles = match (&desc.render.tile_size, &desc.render.tile_order) {
+        (None, None) => None,
+        (size, order) => {
+            let mut tiles = Tiles::default();
+            if let Some(size) = size {
+                if *size.get_ref() == 0 {
+                    return Err(anyhow!("tile_size must be non-zero"))
+                        .with_context(at(size.span()));
+                }
+                tiles.size = *size.get_ref();
+            }
+            if let Some(order) = order {
+                tiles.order = order
+                    .get_ref()
+                    .parse()
+                    .map_err(|e: String| anyhow!(e))
+                    .with_context(at(order.span()))?;
+            }
+            Some(tiles)
+        }
+    };
+    let region = match &desc.render.region {
+        Some(region) => {
+            let [x, y, width, height] = *region.get_ref();
+            let tile = Tile {
+                x,
+                y,
+                width,
+                height,
+            };
+            if !tile.fits(camera.width, camera.height) {
+                return Err(anyhow!("region has to be a non-empty part of the image"))
+                    .with_context(at(region.span()));
+            }
+            Some(tile)
+        }
+        None => None,
+    };
+
+    let settings = RenderSettings {
+        samples: desc.render.samples,
+        max_bounces: desc.render.max_bounces,
+        seed: desc.render.seed,
+        sampler,
+        filter: Filter::new(filter_kind, filter_radius),
+        aovs: desc.render.aovs,
+        cryptomatte: desc.render.cryptomatte,
+        denoise: desc.render.denoise.then(Denoiser::default),
+        adaptive,
+        tiles,
+        region,
+        display: DisplayTransform {
+            tonemap,
+            exposure: display.exposure,
+            transfer,
+        },
+        ..Default::default()
+    };
+
+    let mut scene = Scene::new(camera, objects, env_map);
+    scene.lights = lights;
+    scene.source_hash = source_hash.finish();
+
+    Ok((scene, settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `src` as a scene file and check that it fails with `expected` somewhere in the
+    /// error, which includes the `file:line` it points at
+    fn assert_load_error(name: &str, src: &str, expected: &str) {
+        let path = std::env::temp_dir().join(format!("scenefile-test-{}.toml", name));
+        fs::write(&path, src).unwrap();
+        let error = load_scene(&path).err().expect("the scene should not load");
+        fs::remove_file(&path).unwrap();
+
+        let error = format!("{:#}", error);
+        assert!(error.contains(expected), "{}", error);
+    }
+
+    #[test]
+    fn conductor_needs_eta_and_k() {
+        let named = "[camera]\nposition = [2.0, 0.0, 1.0]\n\n\
+            [materials.gold]\ntype = \"metal\"\neta = [0.1, 0.4, 1.4]\n\n\
+            [[objects]]\nmesh = \"missing.obj\"\nmaterial = \"gold\"\n";
+        assert_load_error("named", named, ".toml:4: a conductor needs both");
+
+        let inline = "[camera]\nposition = [2.0, 0.0, 1.0]\n\n\
+            [[objects]]\nmesh = \"missing.obj\"\n\
+            material = { type = \"metal\", k = [4.0, 2.4, 1.6] }\n";
+        assert_load_error("inline", inline, ".toml:6: a conductor needs both");
+    }
+
+    #[test]
+    fn invalid_settings_point_at_their_line() {
+        let check = |name, settings: &str, expected| {
+            let src = format!("[camera]\nposition = [2.0, 0.0, 1.0]\n{}", settings);
+            assert_load_error(name, &src, expected)
+        };
+
+        check(
+            "resolution",
+            "[render]\nresolution = [0, 9]\n",
+            ":4: resolution",
+        );
+        check("fov", "fov = 0.0\n", ":3: fov");
+        check(
+            "ortho",
+            "projection = \"orthographic\"\northo_height = 0.0\n",
+            ":4: ortho",
+        );
+        check(
+            "aperture",
+            "aperture = 0.1\nf_stop = 2.8\n",
+            ":4: camera can have",
+        );
+        check(
+            "shutter",
+            "f_stop = 2.8\niso = 100.0\nshutter = -1.0\n",
+            ":5: iso and",
+        );
+        check(
+            "exposure",
+            "ev100 = 0.0\niso = 100.0\n",
+            ":3: camera exposure",
+        );
+        check("white", "white_balance = 0.0\n", ":3: white_balance");
+        check(
+            "env",
+            "[environment]\nhdri = \"sky.exr\"\ncolor = 1.0\n",
+            ":5: environment",
+        );
+    }
+}
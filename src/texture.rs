@@ -1,5 +1,6 @@
+use exr::prelude::{ReadChannels, ReadLayers};
 use nalgebra::DMatrix;
-use std::f32::consts::PI;
+use std::{f32::consts::PI, path::Path};
 
 use crate::{Color, Vector2f, Vector3f};
 
@@ -59,11 +60,114 @@ impl Texture for DMatrix<Color> {
     }
 }
 
+/// Closer to the poles than this (sine of the angle to them), `equirectangular` can't tell
+/// directions apart anymore in f32 and doesn't give back what `equirectangular_direction` got
+pub const MIN_SIN_THETA: f32 = 1e-3;
+
 pub fn equirectangular(point: Vector3f) -> Vector2f {
     assert!(point.norm() - 1.0 < 1e-6);
 
     let x = (point.x.atan2(point.y)) / (2.0 * PI) + 0.5;
-    let y = point.z.acos() / PI;
+    // a rotated unit vector can end up slightly longer than 1, acos would give NaN there
+    let y = point.z.clamp(-1.0, 1.0).acos() / PI;
 
     Vector2f::new(x, y)
 }
+
+/// Inverse of `equirectangular`, the unit direction a point on the texture is looking at
+pub fn equirectangular_direction(uv: Vector2f) -> Vector3f {
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let theta = uv.y * PI;
+
+    Vector3f::new(
+        theta.sin() * phi.sin(),
+        theta.sin() * phi.cos(),
+        theta.cos(),
+    )
+}
+
+/// Load the RGB channels of the first layer of an EXR file
+pub fn load_exr(path: impl AsRef<Path>) -> anyhow::Result<DMatrix<Color>> {
+    let image = exr::image::read::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .all_layers()
+        .all_attributes()
+        .on_progress(|_| {})
+        .from_file(path)?;
+
+    let layers = image.layer_data;
+    let layer = layers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("image has no layers"))?;
+
+    let width = layer.size.x();
+    let height = layer.size.y();
+
+    let channel = |name: &str| {
+        layer
+            .channel_data
+            .list
+            .iter()
+            .find(|c| c.name == *name)
+            .ok_or_else(|| anyhow::anyhow!("image has no {} channel", name))
+    };
+
+    let r = channel("R")?.sample_data.values_as_f32();
+    let g = channel("G")?.sample_data.values_as_f32();
+    let b = channel("B")?.sample_data.values_as_f32();
+
+    let rgb = r.zip(g).zip(b).map(|((r, g), b)| Color::new(r, g, b));
+
+    Ok(DMatrix::from_iterator(width, height, rgb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::Distribution2D;
+    use nalgebra::Rotation3;
+
+    #[test]
+    fn equirectangular_past_the_poles() {
+        for z in [1.0 + 1e-7, -1.0 - 1e-7] {
+            let uv = equirectangular(Vector3f::new(0.0, 0.0, z));
+            assert!((0.0..=1.0).contains(&uv.x) && (0.0..=1.0).contains(&uv.y));
+
+            let env = DMatrix::from_element(4, 2, Color::new(1.0, 1.0, 1.0));
+            assert_eq!(env.sample_linear(uv), Color::new(1.0, 1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn equirectangular_round_trip() {
+        // brightest towards the poles, where the lookup is the most fragile
+        let distribution =
+            Distribution2D::new(64, 32, |x, y| 1.0 + x as f32 + (y as f32 - 16.0).abs());
+
+        let rotation = Rotation3::from_euler_angles(0.3, 0.7, 1.1);
+
+        // the ends of the unit interval land right at the poles and the seam
+        let us: Vec<f32> = [0.0, 1e-6, 0.999999]
+            .into_iter()
+            .chain((1..32).map(|i| i as f32 / 32.0))
+            .collect();
+
+        for &u1 in &us {
+            for &u2 in &us {
+                let (uv, _) = distribution.sample(u1, u2);
+                if (uv.y * PI).sin() < MIN_SIN_THETA {
+                    continue;
+                }
+
+                // to camera space and back like the scene does, that's where precision gets lost
+                let direction = rotation.inverse() * equirectangular_direction(uv);
+                let back = equirectangular(rotation * direction);
+                let dx = (back.x - uv.x).abs();
+                assert!(dx.min(1.0 - dx) < 1e-4, "{} {}", uv, back);
+                assert!((back.y - uv.y).abs() < 1e-4, "{} {}", uv, back);
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::str::FromStr;
+
+/// A rectangle of pixels, from the top left corner of the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    /// The whole image
+    pub fn full(width: usize, height: usize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Whether the tile lies inside an image of this size, and has any pixels at all
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.x + self.width <= width
+            && self.y + self.height <= height
+    }
+
+    /// Index in the image (x first, `image_width` wide) of pixel `i` of the tile
+    pub fn pixel(&self, i: usize, image_width: usize) -> usize {
+        (self.y + i / self.width) * image_width + self.x + i % self.width
+    }
+
+    /// Indices in the image of all pixels of the tile
+    pub fn pixels(&self, image_width: usize) -> Vec<usize> {
+        (0..self.width * self.height)
+            .map(|i| self.pixel(i, image_width))
+            .collect()
+    }
+}
+
+/// Which tile gets rendered after which
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TileOrder {
+    /// rows from the top
+    Scanline,
+    /// outwards from the center, where the interesting part usually is
+    #[default]
+    Spiral,
+    /// along a Hilbert curve, every tile is next to the one before it
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!(
+                "unknown tile order '{}', expected scanline, spiral or hilbert",
+                s
+            )),
+        }
+    }
+}
+
+/// Render tile by tile instead of in passes over the whole image
+#[derive(Clone, Copy, Debug)]
+pub struct Tiles {
+    /// width and height in pixels, tiles at the right and bottom edges can be smaller
+    pub size: usize,
+    pub order: TileOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            order: TileOrder::default(),
+        }
+    }
+}
+
+impl Tiles {
+    /// Split `region` into tiles, in the order they should be rendered
+    pub fn split(&self, region: Tile) -> Vec<Tile> {
+        let size = self.size.max(1);
+        let columns = region.width.div_ceil(size);
+        let rows = region.height.div_ceil(size);
+
+        let cells = match self.order {
+            TileOrder::Scanline => (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .collect(),
+            TileOrder::Spiral => spiral(columns, rows),
+            TileOrder::Hilbert => hilbert(columns, rows),
+        };
+
+        cells
+            .into_iter()
+            .map(|(column, row)| {
+                let x = column * size;
+                let y = row * size;
+                Tile {
+                    x: region.x + x,
+                    y: region.y + y,
+                    width: size.min(region.width - x),
+                    height: size.min(region.height - y),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Cells of a grid, walking around the center in growing squares
+fn spiral(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let mut cells = Vec::with_capacity(columns * rows);
+    let (mut x, mut y) = ((columns as isize - 1) / 2, (rows as isize - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+    // right 1, down 1, left 2, up 2, right 3... until every cell was passed
+    let mut length = 1;
+    let mut direction = 0;
+    while cells.len() < columns * rows {
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction % 4];
+            for _ in 0..length {
+                if x >= 0 && y >= 0 && x < columns as isize && y < rows as isize {
+                    cells.push((x as usize, y as usize));
+                }
+                x += dx;
+                y += dy;
+            }
+            direction += 1;
+        }
+        length += 1;
+    }
+
+    cells
+}
+
+/// Cells of a grid in the order a Hilbert curve over the smallest power of two square around
+/// it visits them
+fn hilbert(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let n = columns.max(rows).next_power_of_two();
+
+    (0..n * n)
+        .map(|d| hilbert_cell(n, d))
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+/// Point `d` along the Hilbert curve through an `n` by `n` grid
+fn hilbert_cell(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        // rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(usize, usize); 7] = [(1, 1), (1, 5), (5, 1), (2, 2), (3, 5), (8, 8), (9, 4)];
+
+    fn assert_every_cell_once(cells: Vec<(usize, usize)>, columns: usize, rows: usize) {
+        let mut seen = vec![0; columns * rows];
+        for (x, y) in cells {
+            assert!(
+                x < columns && y < rows,
+                "({}, {}) outside {}x{}",
+                x,
+                y,
+                columns,
+                rows
+            );
+            seen[y * columns + x] += 1;
+        }
+        assert!(
+            seen.iter().all(|&n| n == 1),
+            "{:?} in {}x{}",
+            seen,
+            columns,
+            rows
+        );
+    }
+
+    #[test]
+    fn spiral_visits_every_cell_once() {
+        for (columns, rows) in SIZES {
+            assert_every_cell_once(spiral(columns, rows), columns, rows);
+        }
+    }
+
+    #[test]
+    fn hilbert_visits_every_cell_once() {
+        for (columns, rows) in SIZES {
+            assert_every_cell_once(hilbert(columns, rows), columns, rows);
+        }
+    }
+
+    #[test]
+    fn tiles_cover_the_region_once() {
+        let (width, height) = (100, 60);
+        let region = Tile {
+            x: 10,
+            y: 5,
+            width: 75,
+            height: 50,
+        };
+
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let tiles = Tiles { size: 16, order };
+            let mut seen = vec![0; width * height];
+            for tile in tiles.split(region) {
+                assert!(tile.fits(width, height));
+                for pixel in tile.pixels(width) {
+                    seen[pixel] += 1;
+                }
+            }
+
+            let expected = |i: usize| {
+                let (x, y) = (i % width, i / width);
+                let inside = (region.x..region.x + region.width).contains(&x)
+                    && (region.y..region.y + region.height).contains(&y);
+                inside as i32
+            };
+            assert!(seen.iter().enumerate().all(|(i, &n)| n == expected(i)));
+        }
+    }
+}